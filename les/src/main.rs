@@ -1,21 +1,42 @@
 use anyhow::Result;
+use chrono::format::{strftime::StrftimeItems, ParseError};
 use chrono::{DateTime, Local, Utc};
-use clap::{Parser, ValueEnum};
-use les_core::{PatternMode, Query};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
+use les_core::{
+    read_frame, write_frame, ContentEncoding, ExtensionStats, GcStats, Index, IndexEvent,
+    IndexStats, MultiLogic,
+    PatternMode, Query, RebuildStats, WalkOptions, DEFAULT_CONTENT_LINE_MAX_LEN,
+};
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
+use std::fs;
+use std::io::{self, IsTerminal};
 use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{self, ExitCode, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const AUTO_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit status when a query matched nothing.
+const EXIT_NO_MATCH: u8 = 1;
+/// Exit status for errors: daemon unreachable, invalid query, etc. (as clap uses).
+const EXIT_ERROR: u8 = 2;
 
 #[derive(Parser, Debug)]
 #[command(name = "les", about = "Linux Everything-style search client")]
 struct Args {
-    /// Query pattern (file name/path)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Query pattern (file name/path). In glob mode, separate several globs with `;`
+    /// (e.g. "*.rs;*.toml") to match any of them
     pattern: Option<String>,
 
-    /// Pattern mode
-    ///#[arg(long, value_enum, default_value_t = Mode::Substr)]
-    #[arg(long, value_enum, default_value_t = Mode::Substr)]
-    mode: Mode,
+    /// Pattern mode (default: $LES_DEFAULT_MODE, or substr)
+    #[arg(long, value_enum)]
+    mode: Option<Mode>,
 
     /// Only files
     #[arg(long)]
@@ -26,11 +47,11 @@ struct Args {
     dirs_only: bool,
 
     /// Roots to search (must be subset of daemon roots)
-    #[arg(long, num_args = 1..)]
+    #[arg(long, num_args = 1.., value_hint = ValueHint::DirPath)]
     roots: Vec<String>,
 
     /// Exclude substring filters
-    #[arg(long)]
+    #[arg(long, value_hint = ValueHint::DirPath)]
     exclude: Vec<String>,
 
     /// Minimum size in bytes
@@ -49,32 +70,277 @@ struct Args {
     #[arg(long)]
     max_mtime: Option<i64>,
 
+    /// Only entries modified within this long ago, e.g. 90s, 15m, 2h, 1d, 1w
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    newer_than: Option<Duration>,
+
+    /// Only entries modified at least this long ago (same units as --newer-than)
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    older_than: Option<Duration>,
+
     /// Content search string (slow)
     #[arg(long)]
     content: Option<String>,
 
+    /// Memory-map large files during content search
+    #[arg(long)]
+    content_mmap: bool,
+
+    /// Don't read symlinked files for --content; symlinks never match a content search
+    #[arg(long)]
+    no_follow_symlinks_in_content: bool,
+
+    /// Cut matching lines shown for --content to this many bytes
+    #[arg(long, default_value_t = DEFAULT_CONTENT_LINE_MAX_LEN)]
+    content_line_max_len: usize,
+
+    /// How file bytes are decoded for --content (auto: UTF-8, else Latin-1)
+    #[arg(long, value_enum, default_value_t = Encoding::Utf8)]
+    content_encoding: Encoding,
+
+    /// Flags for --mode regex: any of i (ignore case), m (multi-line), s (. matches
+    /// newline), x (ignore whitespace), e.g. "ix"
+    #[arg(long, value_name = "FLAGS")]
+    regex_flags: Option<String>,
+
+    /// Match the pattern against the file name only, not the full path
+    #[arg(long)]
+    filename_only: bool,
+
+    /// Only show indexed ancestors (parent directories) of this path
+    #[arg(long, value_hint = ValueHint::AnyPath)]
+    parents_of: Option<String>,
+
+    /// List results nearest to this path first (fewest differing path components)
+    #[arg(long, value_hint = ValueHint::AnyPath)]
+    near: Option<String>,
+
+    /// Only show sparse files (apparent size well above allocated size)
+    #[arg(long)]
+    sparse: bool,
+
+    /// Only show files with an execute bit set
+    #[arg(long)]
+    executable: bool,
+
+    /// Only show files starting with these bytes, as hex (e.g. 504b for ZIP); needs lesd --fingerprint
+    #[arg(long, value_name = "HEX", value_parser = parse_hex)]
+    starts_with: Option<HexBytes>,
+
+    /// Only show files this process (as seen by lesd) currently has open
+    #[arg(long, value_name = "PID")]
+    modified_by_pid: Option<u32>,
+
+    /// Only show entries carrying this tag (repeat to require several)
+    #[arg(long = "has-tag")]
+    has_tags: Vec<String>,
+
     /// Unix socket path (must match lesd)
-    #[arg(long, default_value = "/run/lesd.sock")]
+    #[arg(
+        long,
+        global = true,
+        default_value = "/run/lesd.sock",
+        value_hint = ValueHint::FilePath
+    )]
     socket: String,
 
-    /// Interactive mode: repeatedly prompt for pattern
+    /// Output format for query results (`-`, `ndjson` and `ldjson` are aliases for jsonl)
+    #[arg(short, long, value_enum)]
+    output: Option<OutputFormat>,
+
+    /// Timestamp format for table output: iso, unix, rfc2822, or a strftime string
+    #[arg(long, default_value = DEFAULT_TIME_FORMAT, value_parser = parse_time_format)]
+    time_format: TimeFormat,
+
+    /// Interactive mode: repeatedly prompt for pattern (a PATTERN argument runs first)
     #[arg(short, long)]
     interactive: bool,
+
+    /// In interactive mode, don't print a prompt (for patterns piped via stdin)
+    #[arg(long)]
+    no_prompt: bool,
+
+    /// Print the socket, index age, and match/scan counts and timing for each query
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Start lesd in the background if the socket is not accepting connections
+    #[arg(long, global = true)]
+    auto_start: bool,
+
+    /// Index --roots in-process and search that, without lesd (slow: walks every time)
+    #[arg(long, requires = "roots", conflicts_with = "auto_start")]
+    no_daemon: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show index statistics reported by the daemon
+    Stats {
+        /// Also break entry counts and sizes down per indexed root
+        #[arg(long)]
+        by_root: bool,
+        /// Also count entries modified within this long ago, e.g. 24h (units as --newer-than)
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        recent: Option<Duration>,
+    },
+    /// Ask the daemon to rebuild its index from scratch
+    Rebuild,
+    /// Index maintenance
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+    /// Count indexed files per extension, most common first, with their share and size
+    #[command(alias = "count-by-extension")]
+    CountExt,
+    /// Print each change the daemon makes to its index, until interrupted
+    Subscribe,
+    /// Print a shell completion script to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Drop indexed entries whose paths no longer exist, e.g. deleted while lesd was down
+    Gc,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// `<type>\t<size>\t<mtime>\t<path>` lines
+    Table,
+    /// One JSON object per line
+    #[value(aliases = ["-", "ndjson", "ldjson"])]
+    Jsonl,
+}
+
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Clone, Debug)]
+enum TimeFormat {
+    /// ISO 8601 / RFC 3339 with UTC offset
+    Iso,
+    /// Epoch seconds
+    Unix,
+    Rfc2822,
+    Strftime(String),
+}
+
+impl TimeFormat {
+    fn format(&self, dt: DateTime<Local>) -> String {
+        match self {
+            TimeFormat::Iso => dt.to_rfc3339(),
+            TimeFormat::Unix => dt.timestamp().to_string(),
+            TimeFormat::Rfc2822 => dt.to_rfc2822(),
+            TimeFormat::Strftime(f) => dt.format(f).to_string(),
+        }
+    }
+}
+
+/// Parse an age such as `90s`, `15m`, `2h`, `1d` or `1w`; a bare number is seconds.
+fn parse_age(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| format!("invalid age: {s}"))?;
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit in {s} (use s, m, h, d or w)")),
+    };
+    n.checked_mul(secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("age too large: {s}"))
+}
+
+/// Inverse of `parse_age`, in the largest unit that divides `age` evenly.
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    for (unit, n) in [
+        ("w", 7 * 24 * 60 * 60),
+        ("d", 24 * 60 * 60),
+        ("h", 60 * 60),
+        ("m", 60),
+    ] {
+        if secs >= n && secs.is_multiple_of(n) {
+            return format!("{}{unit}", secs / n);
+        }
+    }
+    format!("{secs}s")
+}
+
+/// Bytes given on the command line as hex. An alias, because clap would read
+/// `Option<Vec<u8>>` as a repeatable argument.
+type HexBytes = Vec<u8>;
+
+/// Parse bytes written as hex digits, e.g. `504b0304`.
+fn parse_hex(s: &str) -> Result<HexBytes, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("invalid hex: {s} (use two digits per byte)"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("invalid hex: {s}")))
+        .collect()
+}
+
+fn parse_time_format(s: &str) -> Result<TimeFormat, String> {
+    match s {
+        "iso" => Ok(TimeFormat::Iso),
+        "unix" => Ok(TimeFormat::Unix),
+        "rfc2822" => Ok(TimeFormat::Rfc2822),
+        f => {
+            // Reject bad specifiers up front; `format` would panic on them mid-output.
+            StrftimeItems::new(f)
+                .parse()
+                .map_err(|e: ParseError| format!("invalid strftime format: {e}"))?;
+            Ok(TimeFormat::Strftime(f.to_string()))
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
 enum Mode {
     Glob,
+    /// Glob against the parent directory's name
+    GlobDir,
     Regex,
     Substr,
+    /// Case-insensitive start of the name; `src/ma` also requires a parent named `src`
+    Prefix,
 }
 
 impl From<Mode> for PatternMode {
     fn from(m: Mode) -> Self {
         match m {
             Mode::Glob => PatternMode::Glob,
+            Mode::GlobDir => PatternMode::GlobDir,
             Mode::Regex => PatternMode::Regex,
             Mode::Substr => PatternMode::Substr,
+            Mode::Prefix => PatternMode::Prefix,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Encoding {
+    Utf8,
+    Latin1,
+    Auto,
+}
+
+impl From<Encoding> for ContentEncoding {
+    fn from(e: Encoding) -> Self {
+        match e {
+            Encoding::Utf8 => ContentEncoding::Utf8,
+            Encoding::Latin1 => ContentEncoding::Latin1,
+            Encoding::Auto => ContentEncoding::Auto,
         }
     }
 }
@@ -82,8 +348,16 @@ impl From<Mode> for PatternMode {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Request {
-    Query { query: Query },
+    Query { query: Box<Query> },
+    Stats {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<i64>,
+    },
+    Rebuild,
+    Gc,
+    ExtensionStats,
     Ping,
+    Subscribe,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -91,112 +365,540 @@ enum Request {
 enum Response {
     Pong,
     QueryResult(les_core::QueryResult),
+    Stats(IndexStats),
+    Rebuilt(RebuildStats),
+    Collected(GcStats),
+    ExtensionStats { extensions: Vec<ExtensionStats> },
+    Event(IndexEvent),
     Error { message: String },
 }
 
+/// Answer queries from `local` when searching without the daemon, and send
+/// everything else to lesd.
+fn dispatch(local: Option<&Index>, socket: &str, req: &Request) -> Result<Response> {
+    match (local, req) {
+        (Some(index), Request::Query { query }) => Ok(match index.run_query(query) {
+            Ok(r) => Response::QueryResult(r),
+            Err(e) => Response::Error {
+                message: e.to_string(),
+            },
+        }),
+        _ => send_request(socket, req),
+    }
+}
+
+/// Build a throwaway index of `roots` for `--no-daemon`.
+fn local_index(roots: &[String], excludes: &[String]) -> Result<Index> {
+    let mut index = Index::open_memory()?;
+    let stats = index.rebuild(roots, excludes, WalkOptions::default())?;
+    if stats.error_count > 0 {
+        eprintln!(
+            "warning: skipped {} entries that could not be read",
+            stats.error_count
+        );
+    }
+    Ok(index)
+}
+
 fn send_request(socket: &str, req: &Request) -> Result<Response> {
     let mut stream = UnixStream::connect(socket)?;
     let data = serde_json::to_string(req)?;
-    stream.write_all(data.as_bytes())?;
-    stream.shutdown(std::net::Shutdown::Write)?;
+    write_frame(&mut stream, data.as_bytes())?;
 
-    let mut buf = String::new();
-    stream.read_to_string(&mut buf)?;
-    let resp: Response = serde_json::from_str(&buf)?;
+    let body = read_frame(&mut stream)?
+        .ok_or_else(|| anyhow::anyhow!("daemon closed the connection without responding"))?;
+    let resp: Response = serde_json::from_slice(&body)?;
     Ok(resp)
 }
 
-fn print_results(resp: Response) {
+/// Subscribe to index events and print them as they arrive, until the daemon
+/// closes the connection.
+fn subscribe(socket: &str, output: Option<OutputFormat>, time_format: &TimeFormat) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    let data = serde_json::to_string(&Request::Subscribe)?;
+    write_frame(&mut stream, data.as_bytes())?;
+    while let Some(body) = read_frame(&mut stream)? {
+        print_results(serde_json::from_slice(&body)?, output, time_format);
+    }
+    Ok(())
+}
+
+/// Connect to the daemon, spawning `lesd` and waiting for its socket if nothing is listening.
+fn ensure_daemon(socket: &str) -> Result<()> {
+    if UnixStream::connect(socket).is_ok() {
+        return Ok(());
+    }
+
+    // A file rather than a pipe: lesd outlives us and keeps logging to stderr.
+    let log_path = format!("{socket}.log");
+    let log = fs::File::create(&log_path)
+        .map_err(|e| anyhow::anyhow!("cannot create {log_path}: {e}"))?;
+    eprintln!("lesd is not running on {socket}, starting it (output in {log_path})...");
+    let mut child = process::Command::new("lesd")
+        .args(["--rebuild", "--socket", socket])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log)
+        .spawn()?;
+
+    let deadline = Instant::now() + AUTO_START_TIMEOUT;
+    while Instant::now() < deadline {
+        if UnixStream::connect(socket).is_ok() {
+            return Ok(());
+        }
+        if let Some(status) = child.try_wait()? {
+            let output = fs::read_to_string(&log_path).unwrap_or_default();
+            anyhow::bail!(
+                "lesd exited ({status}) before listening on {socket}:\n{}",
+                output.trim_end()
+            );
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    anyhow::bail!(
+        "lesd did not start listening on {socket} within {}s (see {log_path})",
+        AUTO_START_TIMEOUT.as_secs()
+    )
+}
+
+fn print_results(resp: Response, output: Option<OutputFormat>, time_format: &TimeFormat) {
     match resp {
         Response::Pong => println!("OK (pong)"),
         Response::Error { message } => eprintln!("Error: {message}"),
+        Response::Stats(stats) => print_stats(&stats, false),
+        Response::Rebuilt(stats) => print_rebuild_stats(&stats),
+        Response::Collected(stats) => println!(
+            "Removed {} stale entries in {:.2?}",
+            stats.removed, stats.duration
+        ),
+        Response::ExtensionStats { extensions } if output == Some(OutputFormat::Jsonl) => {
+            for s in extensions {
+                match serde_json::to_string(&s) {
+                    Ok(line) => println!("{line}"),
+                    Err(err) => eprintln!("Error: {err}"),
+                }
+            }
+        }
+        Response::ExtensionStats { extensions } => print_extension_stats(&extensions),
+        Response::Event(event) if output == Some(OutputFormat::Jsonl) => {
+            match serde_json::to_string(&event) {
+                Ok(line) => println!("{line}"),
+                Err(err) => eprintln!("Error: {err}"),
+            }
+        }
+        Response::Event(event) => println!("{}\t{}", event.kind, event.path),
+        Response::QueryResult(qr) if output == Some(OutputFormat::Jsonl) => {
+            for e in qr {
+                match serde_json::to_string(&e) {
+                    Ok(line) => println!("{line}"),
+                    Err(err) => eprintln!("Error: {err}"),
+                }
+            }
+        }
         Response::QueryResult(qr) => {
-            for e in qr.entries {
-                let dt = DateTime::<Utc>::from_timestamp(e.mtime, 0)
+            // Paths go out verbatim; warn once if one would inject escape codes into a pipe.
+            let warn_escapes = output.is_none() && !io::stdout().is_terminal();
+            let mut warned = false;
+            for e in qr {
+                if warn_escapes && !warned && e.path().contains('\x1b') {
+                    eprintln!("warning: output contains ANSI escape sequences in file names");
+                    warned = true;
+                }
+                let dt = DateTime::<Utc>::from_timestamp(e.mtime(), 0)
                     .unwrap_or_else(|| DateTime::<Utc>::from_timestamp(0, 0).unwrap())
                     .with_timezone(&Local);
                 println!(
                     "{}\t{}\t{}\t{}",
-                    if e.is_dir { "d" } else { "-" },
-                    e.size,
-                    dt.format("%Y-%m-%d %H:%M:%S"),
-                    e.path
+                    if e.is_dir() { "d" } else { "-" },
+                    e.size(),
+                    time_format.format(dt),
+                    Path::new(e.path()).display()
                 );
+                for hit in e.content_hits() {
+                    println!("  {}:{}", hit.line_num, hit.line);
+                }
+            }
+        }
+    }
+}
+
+fn print_stats(stats: &IndexStats, by_root: bool) {
+    println!("Entries: {}", stats.entries);
+    println!("Files: {}", stats.files);
+    println!("Directories: {}", stats.dirs);
+    println!("Total size: {} bytes", stats.total_size);
+    if let Some(db_size) = stats.db_size {
+        println!("Database size: {db_size} bytes");
+    }
+    println!("Watched paths: {}", stats.watch_count);
+    match stats.last_rebuilt {
+        Some(t) => println!(
+            "Index built: {}",
+            format_relative(Local::now().timestamp() - t.timestamp())
+        ),
+        None => println!("Index built: never"),
+    }
+    if by_root {
+        for r in &stats.by_root {
+            println!("  {}: {} entries, {} bytes", r.root, r.entries, r.total_size);
+        }
+    }
+}
+
+/// `<extension>\t<files>\t<share of files>\t<total size>` lines.
+fn print_extension_stats(extensions: &[ExtensionStats]) {
+    let total: usize = extensions.iter().map(|s| s.files).sum();
+    for s in extensions {
+        let ext = if s.extension.is_empty() {
+            "(none)"
+        } else {
+            &s.extension
+        };
+        println!(
+            "{ext}\t{}\t{:.1}%\t{}",
+            s.files,
+            100.0 * s.files as f64 / total as f64,
+            s.total_size
+        );
+    }
+}
+
+fn print_rebuild_stats(stats: &RebuildStats) {
+    println!(
+        "Rebuilt index: {} entries in {:.2?}",
+        stats.entries_added, stats.duration
+    );
+    println!("Directories skipped: {}", stats.dirs_skipped);
+    if stats.permission_denied > 0 {
+        println!(
+            "Errors: {} ({} permission denied)",
+            stats.error_count, stats.permission_denied
+        );
+    } else {
+        println!("Errors: {}", stats.error_count);
+    }
+    for (path, message) in &stats.errors {
+        println!("  {path}: {message}");
+    }
+}
+
+/// `# Found N matches, ...` line for `--verbose`; `None` for non-query responses.
+fn query_summary(resp: &Response, elapsed: Duration) -> Option<String> {
+    match resp {
+        Response::QueryResult(qr) => Some(format!(
+            "# Found {} matches, scanned {} entries, took {}ms",
+            qr.entries.len(),
+            qr.scanned,
+            elapsed.as_millis()
+        )),
+        _ => None,
+    }
+}
+
+/// `# Connected to ...` line for `--verbose`, with the index age from a stats request.
+fn connection_info(socket: &str) -> String {
+    let age = match send_request(socket, &Request::Stats { since: None }) {
+        Ok(Response::Stats(IndexStats {
+            last_rebuilt: Some(t),
+            ..
+        })) => format!(
+            "index built {}",
+            format_relative(Local::now().timestamp() - t.timestamp())
+        ),
+        Ok(Response::Stats(_)) => "index never built".to_string(),
+        Ok(_) => "index age unknown".to_string(),
+        Err(e) => format!("stats request failed: {e}"),
+    };
+    format!("# Connected to {socket}, {age}")
+}
+
+/// Format an age in seconds as e.g. "2 hours ago".
+fn format_relative(secs: i64) -> String {
+    if secs < 60 {
+        return "just now".to_string();
+    }
+    let (n, unit) = match secs {
+        s if s < 3600 => (s / 60, "minute"),
+        s if s < 86_400 => (s / 3600, "hour"),
+        s => (s / 86_400, "day"),
+    };
+    if n == 1 {
+        format!("1 {unit} ago")
+    } else {
+        format!("{n} {unit}s ago")
+    }
+}
+
+/// Resolve the pattern mode: `--mode` wins, then `LES_DEFAULT_MODE`, then substr.
+fn resolve_mode(mode: Option<Mode>) -> Result<PatternMode> {
+    if let Some(m) = mode {
+        return Ok(m.into());
+    }
+    match std::env::var("LES_DEFAULT_MODE") {
+        Ok(v) => v.parse(),
+        Err(_) => Ok(PatternMode::Substr),
+    }
+}
+
+/// Make `path` absolute and drop `.`/`..` lexically, since results are compared with
+/// it component by component. Falls back to `path` as given if there is no cwd.
+fn absolute_path(path: &str) -> String {
+    let Ok(abs) = std::path::absolute(path) else {
+        return path.to_string();
+    };
+    let mut out = std::path::PathBuf::new();
+    for c in abs.components() {
+        match c {
+            std::path::Component::ParentDir => {
+                out.pop();
             }
+            std::path::Component::CurDir => {}
+            c => out.push(c),
+        }
+    }
+    out.to_string_lossy().into_owned()
+}
+
+fn build_query(args: &Args, pattern: String, mode: PatternMode) -> Query {
+    Query {
+        pattern,
+        mode,
+        patterns: Vec::new(),
+        multi_logic: MultiLogic::All,
+        files_only: args.files_only,
+        dirs_only: args.dirs_only,
+        roots: args.roots.clone(),
+        exclude: args.exclude.clone(),
+        min_size: args.min_size,
+        max_size: args.max_size,
+        min_mtime: args.min_mtime,
+        max_mtime: args.max_mtime,
+        mtime_newer_than: args.newer_than,
+        mtime_older_than: args.older_than,
+        content: args.content.clone(),
+        content_mmap: args.content_mmap,
+        content_encoding: args.content_encoding.into(),
+        follow_symlinks_in_content: !args.no_follow_symlinks_in_content,
+        content_line_max_len: args.content_line_max_len,
+        has_tags: args.has_tags.clone(),
+        regex_flags: args.regex_flags.clone(),
+        match_filename_only: args.filename_only,
+        path_prefix_of: args.parents_of.clone(),
+        sparse_only: args.sparse,
+        executable_only: args.executable,
+        fingerprint_prefix: args.starts_with.clone(),
+        modified_by_pid: args.modified_by_pid,
+        near_path: args.near.as_deref().map(absolute_path),
+    }
+}
+
+fn print_validation_errors(errors: &[String]) {
+    for e in errors {
+        eprintln!("Invalid query: {e}");
+    }
+}
+
+/// Exit status for a response, following grep: 0 if anything matched, 1 if nothing did,
+/// 2 if the daemon reported an error.
+fn exit_code(resp: &Response) -> ExitCode {
+    match resp {
+        Response::QueryResult(qr) if qr.entries.is_empty() => ExitCode::from(EXIT_NO_MATCH),
+        Response::Error { .. } => ExitCode::from(EXIT_ERROR),
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(EXIT_ERROR)
         }
     }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+fn run() -> Result<ExitCode> {
+    let mut args = Args::parse();
+
+    if args.auto_start {
+        ensure_daemon(&args.socket)?;
+    }
+
+    if let (true, Some(_)) = (args.no_daemon, &args.command) {
+        eprintln!("--no-daemon only runs searches, not subcommands");
+        return Ok(ExitCode::from(EXIT_ERROR));
+    }
+
+    if let Some(command) = &args.command {
+        let req = match command {
+            Command::Stats { recent, .. } => Request::Stats {
+                since: recent.map(|age| Local::now().timestamp() - age.as_secs() as i64),
+            },
+            Command::Rebuild => Request::Rebuild,
+            Command::CountExt => Request::ExtensionStats,
+            Command::Admin {
+                command: AdminCommand::Gc,
+            } => Request::Gc,
+            Command::Subscribe => {
+                subscribe(&args.socket, args.output, &args.time_format)?;
+                return Ok(ExitCode::SUCCESS);
+            }
+            Command::Completions { shell } => {
+                clap_complete::generate(*shell, &mut Args::command(), "les", &mut io::stdout());
+                return Ok(ExitCode::SUCCESS);
+            }
+        };
+        let resp = send_request(&args.socket, &req)?;
+        let code = exit_code(&resp);
+        match (command, resp) {
+            (Command::Stats { by_root, recent }, Response::Stats(stats)) => {
+                print_stats(&stats, *by_root);
+                if let (Some(age), Some(n)) = (recent, stats.recent_changes) {
+                    println!("Modified in the last {}: {n}", format_age(*age));
+                }
+            }
+            (_, resp) => print_results(resp, args.output, &args.time_format),
+        }
+        return Ok(code);
+    }
 
     if args.files_only && args.dirs_only {
         eprintln!("--files-only and --dirs-only cannot both be set");
-        std::process::exit(1);
+        return Ok(ExitCode::from(EXIT_ERROR));
+    }
+
+    let mode = resolve_mode(args.mode)?;
+
+    let local = if args.no_daemon {
+        // Entries are stored under absolute paths, so the roots filter must be too.
+        args.roots = args.roots.iter().map(|r| absolute_path(r)).collect();
+        Some(local_index(&args.roots, &args.exclude)?)
+    } else {
+        None
+    };
+
+    // Diagnostics go to stderr so they stay out of piped results; in interactive
+    // mode everything is on the terminal anyway.
+    let note = |line: String| {
+        if args.interactive {
+            println!("{line}");
+        } else {
+            eprintln!("{line}");
+        }
+    };
+    if let (true, Some(index)) = (args.verbose, &local) {
+        note(format!("# Indexed {} entries in-process", index.stats().entries));
+    } else if args.verbose {
+        note(connection_info(&args.socket));
     }
 
     if args.interactive {
         // minimal TUI: read pattern, query, print results
-        use std::io::{self, Write};
+        use std::io::Write;
+        if !args.no_prompt {
+            // Leave the prompt line tidy instead of dying mid-line on Ctrl-C.
+            ctrlc::set_handler(|| {
+                println!();
+                process::exit(0);
+            })?;
+        }
+        // A pattern given on the command line runs once before the first prompt.
+        let mut initial = args.pattern.clone();
         loop {
-            print!("les> ");
-            io::stdout().flush().unwrap();
-
-            let mut p = String::new();
-            if io::stdin().read_line(&mut p).is_err() {
-                break;
-            }
-            let p = p.trim().to_string();
-            if p.is_empty() {
-                break;
-            }
+            let p = if let Some(p) = initial.take() {
+                p
+            } else {
+                if !args.no_prompt {
+                    print!("les> ");
+                    io::stdout().flush()?;
+                }
 
-            let q = Query {
-                pattern: p,
-                mode: args.mode.into(),
-                files_only: args.files_only,
-                dirs_only: args.dirs_only,
-                roots: args.roots.clone(),
-                exclude: args.exclude.clone(),
-                min_size: args.min_size,
-                max_size: args.max_size,
-                min_mtime: args.min_mtime,
-                max_mtime: args.max_mtime,
-                content: args.content.clone(),
+                let mut p = String::new();
+                if io::stdin().read_line(&mut p)? == 0 {
+                    // Ctrl-D / end of piped input
+                    if !args.no_prompt {
+                        println!();
+                    }
+                    break;
+                }
+                let p = p.trim().to_string();
+                if p.is_empty() {
+                    break;
+                }
+                p
             };
-            let req = Request::Query { query: q };
-            match send_request(&args.socket, &req) {
-                Ok(resp) => print_results(resp),
+
+            let q = build_query(&args, p, mode.clone());
+            if let Err(errors) = q.validate() {
+                print_validation_errors(&errors);
+                continue;
+            }
+            let req = Request::Query { query: Box::new(q) };
+            let started = Instant::now();
+            match dispatch(local.as_ref(), &args.socket, &req) {
+                Ok(resp) => {
+                    let summary = query_summary(&resp, started.elapsed());
+                    print_results(resp, args.output, &args.time_format);
+                    if let (true, Some(summary)) = (args.verbose, summary) {
+                        note(summary);
+                    }
+                }
                 Err(e) => eprintln!("Error: {e}"),
             }
         }
 
-        Ok(())
+        Ok(ExitCode::SUCCESS)
     } else {
-        let pattern = args
-            .pattern
-            .unwrap_or_else(|| {
-                eprintln!("Pattern is required in non-interactive mode");
-                std::process::exit(1);
-            });
-
-        let q = Query {
-            pattern,
-            mode: args.mode.into(),
-            files_only: args.files_only,
-            dirs_only: args.dirs_only,
-            roots: args.roots.clone(),
-            exclude: args.exclude.clone(),
-            min_size: args.min_size,
-            max_size: args.max_size,
-            min_mtime: args.min_mtime,
-            max_mtime: args.max_mtime,
-            content: args.content.clone(),
+        let Some(pattern) = args.pattern.clone() else {
+            eprintln!("Pattern is required in non-interactive mode");
+            return Ok(ExitCode::from(EXIT_ERROR));
         };
 
-        let req = Request::Query { query: q };
-        let resp = send_request(&args.socket, &req)?;
-        print_results(resp);
-        Ok(())
+        let q = build_query(&args, pattern, mode);
+        if let Err(errors) = q.validate() {
+            print_validation_errors(&errors);
+            return Ok(ExitCode::from(EXIT_ERROR));
+        }
+
+        let req = Request::Query { query: Box::new(q) };
+        let started = Instant::now();
+        let resp = dispatch(local.as_ref(), &args.socket, &req)?;
+        let summary = query_summary(&resp, started.elapsed());
+        let code = exit_code(&resp);
+        print_results(resp, args.output, &args.time_format);
+        if let (true, Some(summary)) = (args.verbose, summary) {
+            note(summary);
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_age_units() {
+        assert_eq!(parse_age("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_age("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_age("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert_eq!(parse_age("1w"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn parse_age_invalid() {
+        for s in ["", "m", "-5m", "1.5h", "3y", "2 h", "99999999999999999999d"] {
+            assert!(parse_age(s).is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn format_age_round_trip() {
+        for s in ["45s", "30m", "2h", "3d", "2w", "90m"] {
+            assert_eq!(format_age(parse_age(s).unwrap()), s);
+        }
     }
 }
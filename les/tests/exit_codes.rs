@@ -0,0 +1,61 @@
+//! `les` exits like grep: 0 when something matched, 1 when nothing did, 2 on errors.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A directory holding `hello.txt`, unique to this process and test.
+fn tree(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("les-exit-{}-{name}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("hello.txt"), "hi\n").unwrap();
+    dir
+}
+
+/// Run `les --no-daemon` over `dir` with `args` and return its exit code.
+fn les_local(dir: &Path, args: &[&str]) -> i32 {
+    Command::new(env!("CARGO_BIN_EXE_les"))
+        .arg("--no-daemon")
+        .arg("--roots")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap()
+        .status
+        .code()
+        .unwrap()
+}
+
+#[test]
+fn match_exits_zero() {
+    let dir = tree("match");
+    assert_eq!(les_local(&dir, &["--", "hello"]), 0);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn no_match_exits_one() {
+    let dir = tree("no-match");
+    assert_eq!(les_local(&dir, &["--", "nothing-is-called-this"]), 1);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn invalid_pattern_exits_two() {
+    let dir = tree("invalid");
+    assert_eq!(les_local(&dir, &["--mode", "regex", "--", "("]), 2);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn unreachable_daemon_exits_two() {
+    let socket = std::env::temp_dir().join(format!("les-exit-{}-no.sock", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_les"))
+        .arg("--socket")
+        .arg(&socket)
+        .arg("hello")
+        .output()
+        .unwrap()
+        .status;
+    assert_eq!(status.code(), Some(2));
+}
@@ -0,0 +1,150 @@
+//! `Index::run_query` per `PatternMode`, with and without the trigram index.
+//!
+//! Index sizes default to 1M, 5M and 10M entries; set `LES_BENCH_SIZES` (e.g.
+//! `LES_BENCH_SIZES=1000000`) to run fewer. 10M entries with trigrams needs well over
+//! 8 GB of memory. The `filters` group measures queries that combine the pattern
+//! with other filters. Results are summarised in `BENCHMARKS.md`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use les_core::{FileEntry, Index, IndexConfig, PatternMode, Query};
+use std::path::Path;
+
+const DEFAULT_SIZES: &[usize] = &[1_000_000, 5_000_000, 10_000_000];
+const EXTENSIONS: &[&str] = &["rs", "txt", "log", "json"];
+
+/// Each pattern matches the same ten files, so the modes do the same amount of
+/// result work.
+const PATTERNS: &[(&str, &str, PatternMode)] = &[
+    ("glob", "file_0001234?.*", PatternMode::Glob),
+    ("regex", r"file_0001234\d\.", PatternMode::Regex),
+    ("substr", "file_0001234", PatternMode::Substr),
+];
+
+fn sizes() -> Vec<usize> {
+    match std::env::var("LES_BENCH_SIZES") {
+        Ok(s) => s
+            .split(',')
+            .map(|n| {
+                n.trim()
+                    .parse()
+                    .expect("LES_BENCH_SIZES: comma-separated counts")
+            })
+            .collect(),
+        Err(_) => DEFAULT_SIZES.to_vec(),
+    }
+}
+
+/// `/bench/dNNNNN/file_NNNNNNNN.ext`, a thousand files per directory, with sizes
+/// spread evenly over 0..4096.
+fn synthetic_entries(n: usize) -> impl Iterator<Item = FileEntry> {
+    (0..n).map(|i| {
+        let path = format!(
+            "/bench/d{:05}/file_{:08}.{}",
+            i / 1000,
+            i,
+            EXTENSIONS[i % EXTENSIONS.len()]
+        );
+        FileEntry::new(path, false, (i % 4096) as u64, 0)
+    })
+}
+
+fn query(pattern: &str, mode: PatternMode) -> Query {
+    Query {
+        pattern: pattern.to_string(),
+        mode,
+        ..Query::default()
+    }
+}
+
+fn open(db_path: &Path, trigram_index: bool) -> Index {
+    let config = IndexConfig {
+        trigram_index,
+        ..IndexConfig::default()
+    };
+    Index::open_with_config(db_path, config).expect("open bench index")
+}
+
+/// A regex that has to run on every entry it is given, plus `extra` filters.
+fn filtered(extra: impl FnOnce(&mut Query)) -> Query {
+    let mut q = query(r"file_\d+7\.rs$", PatternMode::Regex);
+    extra(&mut q);
+    q
+}
+
+fn populate(n: usize) -> std::path::PathBuf {
+    let db_path = std::env::temp_dir().join(format!("les-bench-{}-{n}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&db_path);
+    open(&db_path, false)
+        .add_entries(synthetic_entries(n))
+        .expect("populate bench index");
+    db_path
+}
+
+fn bench_modes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run_query");
+    group.sample_size(10);
+
+    for n in sizes() {
+        let db_path = populate(n);
+
+        // Reopening loads the entries back and, with trigrams on, builds the postings.
+        for (label, trigrams) in [("scan", false), ("trigram", true)] {
+            let index = open(&db_path, trigrams);
+            for (name, pattern, mode) in PATTERNS {
+                let q = query(pattern, mode.clone());
+                let id = BenchmarkId::new(format!("{name}/{label}"), n);
+                group.bench_with_input(id, &q, |b, q| {
+                    b.iter(|| index.run_query(q).expect("run_query"))
+                });
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&db_path);
+    }
+    group.finish();
+}
+
+/// Each case pairs the regex with filters that reject most or all entries, so the
+/// time shows whether they run before it.
+fn bench_filters(c: &mut Criterion) {
+    let cases: Vec<(&str, Query)> = vec![
+        ("regex", filtered(|_| {})),
+        // 1 in 16 entries kept.
+        ("min_size", filtered(|q| q.min_size = Some(3840))),
+        ("roots", filtered(|q| q.roots = vec!["/bench/d00001".to_string()])),
+        ("exclude", filtered(|q| q.exclude = vec!["/bench/".to_string()])),
+        ("has_tag", filtered(|q| q.has_tags = vec!["keep".to_string()])),
+        (
+            "roots+exclude",
+            filtered(|q| {
+                q.roots = vec!["/bench".to_string()];
+                q.exclude = vec!["/bench/".to_string()];
+            }),
+        ),
+        (
+            "roots+has_tag",
+            filtered(|q| {
+                q.roots = vec!["/bench".to_string()];
+                q.has_tags = vec!["keep".to_string()];
+            }),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("filters");
+    group.sample_size(10);
+    for n in sizes() {
+        let db_path = populate(n);
+        let index = open(&db_path, false);
+        for (name, q) in &cases {
+            group.bench_with_input(BenchmarkId::new(*name, n), q, |b, q| {
+                b.iter(|| index.run_query(q).expect("run_query"))
+            });
+        }
+        drop(index);
+        let _ = std::fs::remove_dir_all(&db_path);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_modes, bench_filters);
+criterion_main!(benches);
@@ -1,35 +1,360 @@
+//! In-memory file index backed by sled, shared by `lesd` and `les`.
+//!
+//! # Thread safety
+//!
+//! `Index` is `Send` and `Sync`. Methods taking `&self` (queries, lookups, stats,
+//! `content_hash`) can run from many threads at once; the only shared state they
+//! touch is the sled database, which does its own locking. Methods that change the
+//! index take `&mut self`, so sharing one between threads that also update it needs
+//! a lock around it, as `lesd` does with `Arc<Mutex<Index>>`. Use an `RwLock` instead
+//! to let queries run in parallel.
+
+use aho_corasick::AhoCorasick;
 use anyhow::Result;
 use chrono::{DateTime, Local};
-use globset::{Glob, GlobSet, GlobSetBuilder};
-use regex::Regex;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
 use sled::Db;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
-use walkdir::WalkDir;
-use bincode; // <-- added
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use jwalk::WalkDir;
+
+mod prefix;
+mod trigram;
+use prefix::PrefixIndex;
+use trigram::TrigramIndex;
+
+#[cfg(feature = "udev")]
+pub mod media;
+
+/// Typed errors returned (wrapped in `anyhow::Error`) by index operations.
+#[derive(Debug, thiserror::Error)]
+pub enum LesError {
+    #[error("invalid path: {0}")]
+    InvalidPath(String),
+    #[error("path not in index: {0}")]
+    NotIndexed(String),
+    #[error("database {0} is locked by another process (is another lesd running?)")]
+    DatabaseLocked(String),
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+}
+
+/// Boxed path predicate produced by `build_path_matcher`.
+pub type Matcher = Box<dyn Fn(&str) -> bool + Send + Sync>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
-    pub path: String,
-    pub is_dir: bool,
-    pub size: u64,
-    pub mtime: i64, // epoch seconds
+    path: String,
+    is_dir: bool,
+    size: u64,
+    mtime: i64, // epoch seconds
+    /// User-assigned tags; set via `Index::tag_entry`, never by the walker.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Position in `Index::roots` of the root this entry was found under.
+    #[serde(default)]
+    root_idx: u16,
+    /// Device and inode numbers (of the symlink target, like `size`).
+    #[serde(default)]
+    dev: u64,
+    #[serde(default)]
+    ino: u64,
+    /// Apparent size is more than twice the allocated blocks (a heuristic).
+    #[serde(default)]
+    is_sparse: bool,
+    /// Permission bits of `st_mode` (`0o7777`), of the symlink target like `size`.
+    #[serde(default)]
+    mode: u32,
+    /// Matching lines from a content search; only filled in query results.
+    #[serde(default)]
+    content_hits: Vec<ContentHit>,
+    /// First and last `FINGERPRINT_HALF` bytes of a file; set when the walk has
+    /// `WalkOptions::fingerprint`.
+    #[serde(default, with = "fingerprint_serde")]
+    content_fingerprint: Option<[u8; FINGERPRINT_LEN]>,
+}
+
+/// Bytes taken from each end of a file for `FileEntry::content_fingerprint`.
+pub const FINGERPRINT_HALF: usize = 64;
+const FINGERPRINT_LEN: usize = 2 * FINGERPRINT_HALF;
+
+/// serde only derives arrays up to 32 elements; go through a byte slice instead.
+mod fingerprint_serde {
+    use super::FINGERPRINT_LEN;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<[u8; FINGERPRINT_LEN]>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.as_ref().map(|b| b.as_slice()).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<[u8; FINGERPRINT_LEN]>, D::Error> {
+        match Option::<Vec<u8>>::deserialize(d)? {
+            Some(v) => v
+                .try_into()
+                .map(Some)
+                .map_err(|v: Vec<u8>| serde::de::Error::invalid_length(v.len(), &"128 bytes")),
+            None => Ok(None),
+        }
+    }
+}
+
+impl FileEntry {
+    /// An entry with no tags, root 0, and zero device/inode numbers, e.g. for
+    /// `Index::add_entry`.
+    pub fn new(path: String, is_dir: bool, size: u64, mtime: i64) -> Self {
+        Self {
+            path,
+            is_dir,
+            size,
+            mtime,
+            tags: Vec::new(),
+            root_idx: 0,
+            dev: 0,
+            ino: 0,
+            is_sparse: false,
+            mode: 0,
+            content_hits: Vec::new(),
+            content_fingerprint: None,
+        }
+    }
+
+    /// Stat `path` (following symlinks) into an entry with no tags, root 0 and no
+    /// fingerprint. The path is stored as given.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let md = fs::metadata(path)?;
+        Ok(entry_from_metadata(path.to_string_lossy().into_owned(), &md))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Size in bytes; 0 for directories.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Modification time in epoch seconds.
+    pub fn mtime(&self) -> i64 {
+        self.mtime
+    }
+
+    /// Seconds since the entry was modified; negative for mtimes in the future.
+    pub fn age_secs(&self) -> i64 {
+        Local::now().timestamp().saturating_sub(self.mtime)
+    }
+
+    /// Time since the entry was modified, or zero for mtimes in the future.
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(u64::try_from(self.age_secs()).unwrap_or(0))
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Position in `Index::roots` of the root this entry was found under.
+    pub fn root_idx(&self) -> u16 {
+        self.root_idx
+    }
+
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.is_sparse
+    }
+
+    /// Permission bits as indexed, e.g. `0o755`; 0 for entries not read from disk.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Whether owner, group or others may execute it. This is the mode bits only,
+    /// not whether the current user can.
+    pub fn is_executable(&self) -> bool {
+        self.mode & 0o111 != 0
+    }
+
+    /// Whether owner, group or others may read it (mode bits only).
+    pub fn is_readable(&self) -> bool {
+        self.mode & 0o444 != 0
+    }
+
+    /// Whether owner, group or others may write it (mode bits only).
+    pub fn is_writable(&self) -> bool {
+        self.mode & 0o222 != 0
+    }
+
+    pub fn content_hits(&self) -> &[ContentHit] {
+        &self.content_hits
+    }
+
+    /// The file's first `FINGERPRINT_HALF` bytes followed by its last ones, each
+    /// zero-padded if the file is shorter (so both halves hold all of a small file).
+    /// `None` for directories, unreadable files, and walks without fingerprinting.
+    pub fn content_fingerprint(&self) -> Option<&[u8; FINGERPRINT_LEN]> {
+        self.content_fingerprint.as_ref()
+    }
+
+    /// Extension of a file's name, as keyed in `Index::by_extension`; `None` for dirs.
+    fn extension(&self) -> Option<&str> {
+        if self.is_dir {
+            return None;
+        }
+        Path::new(&self.path).extension()?.to_str()
+    }
+}
+
+impl TryFrom<&Path> for FileEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(path: &Path) -> Result<Self> {
+        Self::from_path(path)
+    }
+}
+
+impl TryFrom<&str> for FileEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(path: &str) -> Result<Self> {
+        Self::from_path(Path::new(path))
+    }
+}
+
+impl TryFrom<String> for FileEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(path: String) -> Result<Self> {
+        Self::from_path(Path::new(&path))
+    }
 }
 
+/// One line matching `Query::content`, like a line of `grep -n` output.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentHit {
+    /// 1-based line number.
+    pub line_num: usize,
+    /// The line without its newline, truncated to `Query::content_line_max_len` bytes.
+    pub line: String,
+    /// Offset of the start of the line in the file.
+    pub byte_offset: usize,
+}
+
+/// Default for `Query::content_line_max_len`.
+pub const DEFAULT_CONTENT_LINE_MAX_LEN: usize = 200;
+
+fn default_content_line_max_len() -> usize {
+    DEFAULT_CONTENT_LINE_MAX_LEN
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PatternMode {
     Glob,
+    /// Glob matched against the name of the entry's parent directory.
+    #[serde(rename = "glob-dir")]
+    GlobDir,
     Regex,
+    #[default]
     Substr,
+    /// Case-insensitive prefix of the entry's name. Anything up to a last `/` must
+    /// match the end of the parent directory, so `src/ma` finds `src/main.rs`.
+    Prefix,
+}
+
+impl std::fmt::Display for PatternMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PatternMode::Glob => "glob",
+            PatternMode::GlobDir => "glob-dir",
+            PatternMode::Regex => "regex",
+            PatternMode::Substr => "substr",
+            PatternMode::Prefix => "prefix",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::str::FromStr for PatternMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "glob" => Ok(PatternMode::Glob),
+            "glob-dir" => Ok(PatternMode::GlobDir),
+            "regex" => Ok(PatternMode::Regex),
+            "substr" => Ok(PatternMode::Substr),
+            "prefix" => Ok(PatternMode::Prefix),
+            other => Err(anyhow::anyhow!("unknown pattern mode: {other}")),
+        }
+    }
+}
+
+/// Text encoding assumed for content search.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    /// Files that are not valid UTF-8 never match (unless memory-mapped).
+    #[default]
+    Utf8,
+    /// Every byte is one ISO-8859-1 character.
+    Latin1,
+    /// UTF-8 if the file decodes as such, Latin-1 otherwise.
+    Auto,
+}
+
+/// How `Query::patterns` combine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MultiLogic {
+    /// Every pattern must match.
+    #[default]
+    All,
+    /// At least one pattern must match.
+    Any,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Query {
+    /// Deprecated in favour of `patterns`; used as its only element when it is empty.
+    #[serde(default)]
     pub pattern: String,
+    /// Deprecated in favour of `patterns`; see `pattern`.
+    #[serde(default)]
     pub mode: PatternMode,
+    /// Patterns to match, each with its own mode, combined by `multi_logic`.
+    #[serde(default)]
+    pub patterns: Vec<(String, PatternMode)>,
+    #[serde(default)]
+    pub multi_logic: MultiLogic,
     pub files_only: bool,
     pub dirs_only: bool,
     pub roots: Vec<String>,
@@ -38,271 +363,3199 @@ pub struct Query {
     pub max_size: Option<u64>,
     pub min_mtime: Option<i64>,
     pub max_mtime: Option<i64>,
+    /// Only match entries modified within this long before the query runs. Combined
+    /// with `min_mtime` if both are set.
+    #[serde(default)]
+    pub mtime_newer_than: Option<Duration>,
+    /// Only match entries modified at least this long before the query runs.
+    #[serde(default)]
+    pub mtime_older_than: Option<Duration>,
     /// Optional content pattern: if set, do a slower content search.
     pub content: Option<String>,
+    /// Memory-map large files for content search instead of reading them into memory.
+    #[serde(default)]
+    pub content_mmap: bool,
+    /// How file bytes are decoded for `content` search.
+    #[serde(default)]
+    pub content_encoding: ContentEncoding,
+    /// Read through symlinks for `content` search. When false, symlinks never match a
+    /// content query, so nothing outside the indexed tree is read by way of one.
+    #[serde(default = "default_true")]
+    pub follow_symlinks_in_content: bool,
+    /// Longest line (in bytes) kept in a `ContentHit`; longer lines are cut.
+    #[serde(default = "default_content_line_max_len")]
+    pub content_line_max_len: usize,
+    /// Only match entries carrying all of these tags.
+    #[serde(default)]
+    pub has_tags: Vec<String>,
+    /// Flags for regex patterns, as letters: `i` case-insensitive, `m` multi-line,
+    /// `s` `.` matches newlines, `x` ignore whitespace. Same as inline `(?ims)`.
+    #[serde(default)]
+    pub regex_flags: Option<String>,
+    /// Match substr/regex patterns against the file name only (glob always does).
+    #[serde(default)]
+    pub match_filename_only: bool,
+    /// Only match proper ancestors of this path, e.g. `/home` and `/home/alice` for
+    /// `/home/alice/notes.txt`. Compared by path component, not string prefix.
+    #[serde(default)]
+    pub path_prefix_of: Option<String>,
+    /// Only match sparse files (see `FileEntry::is_sparse`).
+    #[serde(default)]
+    pub sparse_only: bool,
+    /// Only match files with an execute bit set (see `FileEntry::is_executable`).
+    /// Directories never match, though they nearly always have one.
+    #[serde(default)]
+    pub executable_only: bool,
+    /// Only match files that start with these bytes, e.g. `b"PK"` for ZIP archives.
+    /// Checked against `FileEntry::content_fingerprint`, so at most `FINGERPRINT_HALF`
+    /// bytes, and files indexed without fingerprints never match.
+    #[serde(default)]
+    pub fingerprint_prefix: Option<Vec<u8>>,
+    /// Only match files this process currently has open (from `/proc/<pid>/fd`).
+    /// Linux only; elsewhere the query fails.
+    #[serde(default)]
+    pub modified_by_pid: Option<u32>,
+    /// Order results by how many path components differ from this path, so files in
+    /// its directory come first (distance 1), then its sibling directories (2), and so
+    /// on. Applied by `run_query`; `query` keeps index order.
+    #[serde(default)]
+    pub near_path: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct QueryResult {
-    pub entries: Vec<FileEntry>,
+/// An empty substring query with no filters, using the same defaults as a query
+/// deserialized with those fields left out: it matches every entry.
+impl Default for Query {
+    fn default() -> Self {
+        Self {
+            pattern: String::new(),
+            mode: PatternMode::Substr,
+            patterns: Vec::new(),
+            multi_logic: MultiLogic::All,
+            files_only: false,
+            dirs_only: false,
+            roots: Vec::new(),
+            exclude: Vec::new(),
+            min_size: None,
+            max_size: None,
+            min_mtime: None,
+            max_mtime: None,
+            mtime_newer_than: None,
+            mtime_older_than: None,
+            content: None,
+            content_mmap: false,
+            content_encoding: ContentEncoding::Utf8,
+            follow_symlinks_in_content: true,
+            content_line_max_len: DEFAULT_CONTENT_LINE_MAX_LEN,
+            has_tags: Vec::new(),
+            regex_flags: None,
+            match_filename_only: false,
+            path_prefix_of: None,
+            sparse_only: false,
+            executable_only: false,
+            fingerprint_prefix: None,
+            modified_by_pid: None,
+            near_path: None,
+        }
+    }
 }
 
-/// Index abstraction: in-memory entries + sled DB on disk.
-pub struct Index {
-    pub entries: Vec<FileEntry>,
-    db: Db,
-}
+impl Query {
+    /// A `QueryBuilder` starting from `Query::default()`.
+    pub fn builder() -> QueryBuilder {
+        QueryBuilder::default()
+    }
 
-impl Index {
-    /// Open or create index DB and load entries into memory
-    pub fn open(db_path: &Path) -> Result<Self> {
-        let db = sled::open(db_path)?;
-        let tree = db.open_tree("entries")?;
-        let mut entries = Vec::new();
+    pub fn with_content_encoding(mut self, encoding: ContentEncoding) -> Self {
+        self.content_encoding = encoding;
+        self
+    }
 
-        for item in tree.iter() {
-            let (_, v) = item?;
-            let e: FileEntry = bincode::deserialize(&v)?;
-            entries.push(e);
+    /// `patterns`, or `pattern` and `mode` for queries built before it existed.
+    pub fn effective_patterns(&self) -> Vec<(&str, &PatternMode)> {
+        if self.patterns.is_empty() {
+            vec![(self.pattern.as_str(), &self.mode)]
+        } else {
+            self.patterns.iter().map(|(p, m)| (p.as_str(), m)).collect()
         }
+    }
 
-        Ok(Self { entries, db })
+    /// Whether every entry matches: empty substring patterns and no filters.
+    fn matches_everything(&self) -> bool {
+        self.effective_patterns()
+            .iter()
+            .all(|(p, m)| p.is_empty() && matches!(m, PatternMode::Substr))
+            && !self.files_only
+            && !self.dirs_only
+            && self.roots.is_empty()
+            && self.exclude.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.min_mtime.is_none()
+            && self.max_mtime.is_none()
+            && self.mtime_newer_than.is_none()
+            && self.mtime_older_than.is_none()
+            && self.content.is_none()
+            && self.has_tags.is_empty()
+            && self.path_prefix_of.is_none()
+            && !self.sparse_only
+            && !self.executable_only
+            && self.fingerprint_prefix.is_none()
+            && self.modified_by_pid.is_none()
     }
 
-    /// Rebuild index from scratch for given roots
-    pub fn rebuild(&mut self, roots: &[String], excludes: &[String]) -> Result<()> {
-        let tree = self.db.open_tree("entries")?;
-        tree.clear()?;
-        self.entries.clear();
+    /// Effective `(min, max)` mtime bounds, turning the relative fields into epoch
+    /// seconds as of now.
+    fn mtime_range(&self) -> (Option<i64>, Option<i64>) {
+        let now = Local::now().timestamp();
+        let ago = |d: Duration| now.saturating_sub(i64::try_from(d.as_secs()).unwrap_or(i64::MAX));
+        let min = self.min_mtime.max(self.mtime_newer_than.map(ago));
+        let max = match (self.max_mtime, self.mtime_older_than.map(ago)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        (min, max)
+    }
+
+    /// Check the query for contradictory filters and patterns that cannot compile.
+    pub fn validate(&self) -> std::result::Result<(), Vec<String>> {
+        let mut errors = Vec::new();
 
-        for root in roots {
-            self.index_root(Path::new(root), excludes)?;
+        for (pattern, mode) in self.effective_patterns() {
+            match mode {
+                PatternMode::Glob | PatternMode::GlobDir | PatternMode::Regex
+                    if pattern.is_empty() =>
+                {
+                    errors.push(format!("empty pattern in {mode} mode"));
+                }
+                PatternMode::Glob | PatternMode::GlobDir => {
+                    for pattern in glob_patterns(pattern) {
+                        if let Err(e) = Glob::new(pattern) {
+                            errors.push(format!("invalid glob: {e}"));
+                        }
+                    }
+                }
+                PatternMode::Regex => {
+                    if let Err(e) = build_regex(pattern, self.regex_flags.as_deref()) {
+                        errors.push(format!("invalid regex: {e}"));
+                    }
+                }
+                PatternMode::Substr | PatternMode::Prefix => {}
+            }
         }
 
-        // Persist entries into DB
-        for entry in &self.entries {
-            let key = entry.path.as_bytes();
-            let val = bincode::serialize(entry)?;
-            tree.insert(key, val)?;
+        if self.files_only && self.dirs_only {
+            errors.push("files_only and dirs_only cannot both be set".to_string());
+        }
+        if !self.roots.is_empty() && self.roots.iter().all(|r| r.is_empty()) {
+            errors.push("roots are all empty strings".to_string());
+        }
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size) {
+            if min > max {
+                errors.push(format!("min_size ({min}) is greater than max_size ({max})"));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_mtime, self.max_mtime) {
+            if min > max {
+                errors.push(format!("min_mtime ({min}) is greater than max_mtime ({max})"));
+            }
+        }
+        if let Some(prefix) = &self.fingerprint_prefix {
+            if prefix.len() > FINGERPRINT_HALF {
+                errors.push(format!(
+                    "fingerprint_prefix is {} bytes; at most {FINGERPRINT_HALF} are fingerprinted",
+                    prefix.len()
+                ));
+            }
+        }
+        if let (Some(newer), Some(older)) = (self.mtime_newer_than, self.mtime_older_than) {
+            if older > newer {
+                errors.push(format!(
+                    "mtime_older_than ({older:?}) is greater than mtime_newer_than ({newer:?})"
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
-        tree.flush()?;
-        Ok(())
     }
+}
 
-    fn index_root(&mut self, root: &Path, excludes: &[String]) -> Result<()> {
-        for e in WalkDir::new(root)
-            .follow_links(false)
-            .into_iter()
-            .filter_entry(|de| !should_skip(de.path(), excludes))
-        {
-            let e = match e {
-                Ok(v) => v,
-                Err(_) => continue,
-            };
+/// Builds a `Query` field by field and checks it with `Query::validate` in
+/// `build`, so a bad combination fails where the query is made rather than when
+/// it runs. Fields not set keep their `Query::default()` values.
+#[derive(Debug, Clone, Default)]
+pub struct QueryBuilder {
+    query: Query,
+}
 
-            let path = e.path();
-            let md = match fs::metadata(path) {
-                Ok(m) => m,
-                Err(_) => continue,
-            };
+impl QueryBuilder {
+    /// Set the single pattern and its mode.
+    pub fn pattern(mut self, pattern: impl Into<String>, mode: PatternMode) -> Self {
+        self.query.pattern = pattern.into();
+        self.query.mode = mode;
+        self
+    }
 
-            let is_dir = md.is_dir();
-            let size = if is_dir { 0 } else { md.size() };
-            let mtime = match md.modified() {
-                Ok(t) => {
-                    let dt: DateTime<Local> = t.into();
-                    dt.timestamp()
-                }
-                Err(_) => 0,
-            };
+    /// Add a pattern to `patterns`, combined with the others per `multi_logic`.
+    pub fn add_pattern(mut self, pattern: impl Into<String>, mode: PatternMode) -> Self {
+        self.query.patterns.push((pattern.into(), mode));
+        self
+    }
 
-            let entry = FileEntry {
-                path: path.to_string_lossy().to_string(),
-                is_dir,
-                size,
-                mtime,
-            };
-            self.entries.push(entry);
-        }
-        Ok(())
+    /// How `patterns` are combined.
+    pub fn multi_logic(mut self, value: MultiLogic) -> Self {
+        self.query.multi_logic = value;
+        self
     }
 
-    /// Apply FS change: simple strategy – reindex that path or remove it
-    pub fn update_path(&mut self, path: &Path, excludes: &[String]) {
-        let s = path.to_string_lossy().to_string();
+    pub fn files_only(mut self, value: bool) -> Self {
+        self.query.files_only = value;
+        self
+    }
 
-        // Remove any existing record for this path
-        self.entries.retain(|e| e.path != s);
+    pub fn dirs_only(mut self, value: bool) -> Self {
+        self.query.dirs_only = value;
+        self
+    }
 
-        if should_skip(path, excludes) {
-            if let Ok(tree) = self.db.open_tree("entries") {
-                let _ = tree.remove(s.as_bytes());
-                let _ = tree.flush();
-            }
-            return;
-        }
+    pub fn min_size(mut self, value: u64) -> Self {
+        self.query.min_size = Some(value);
+        self
+    }
 
-        if let Ok(md) = fs::metadata(path) {
-            let is_dir = md.is_dir();
-            let size = if is_dir { 0 } else { md.size() };
-            let mtime = match md.modified() {
-                Ok(t) => {
-                    let dt: DateTime<Local> = t.into();
-                    dt.timestamp()
-                }
-                Err(_) => 0,
-            };
+    pub fn max_size(mut self, value: u64) -> Self {
+        self.query.max_size = Some(value);
+        self
+    }
 
-            let entry = FileEntry {
-                path: s.clone(),
-                is_dir,
-                size,
-                mtime,
-            };
+    pub fn min_mtime(mut self, value: i64) -> Self {
+        self.query.min_mtime = Some(value);
+        self
+    }
 
-            // Serialize BEFORE pushing entry (fixes borrow-of-moved-value)
-            if let Ok(tree) = self.db.open_tree("entries") {
-                if let Ok(val) = bincode::serialize(&entry) {
-                    let _ = tree.insert(s.as_bytes(), val);
-                    let _ = tree.flush();
-                }
-            }
+    pub fn max_mtime(mut self, value: i64) -> Self {
+        self.query.max_mtime = Some(value);
+        self
+    }
 
-            // Now we can move entry
-            self.entries.push(entry);
+    pub fn mtime_newer_than(mut self, value: Duration) -> Self {
+        self.query.mtime_newer_than = Some(value);
+        self
+    }
 
-        } else {
-            // path no longer exists -> remove from DB
-            if let Ok(tree) = self.db.open_tree("entries") {
-                let _ = tree.remove(s.as_bytes());
-                let _ = tree.flush();
-            }
-        }
+    pub fn mtime_older_than(mut self, value: Duration) -> Self {
+        self.query.mtime_older_than = Some(value);
+        self
     }
 
-    pub fn run_query(&self, q: &Query) -> Result<QueryResult> {
-        let matcher = build_matcher(q)?;
-        let mut out = Vec::new();
+    pub fn content_mmap(mut self, value: bool) -> Self {
+        self.query.content_mmap = value;
+        self
+    }
 
-        'outer: for e in &self.entries {
-            if q.files_only && e.is_dir {
-                continue;
-            }
-            if q.dirs_only && !e.is_dir {
-                continue;
-            }
+    pub fn content_encoding(mut self, value: ContentEncoding) -> Self {
+        self.query.content_encoding = value;
+        self
+    }
 
-            if let Some(min) = q.min_size {
-                if e.size < min {
-                    continue;
-                }
-            }
-            if let Some(max) = q.max_size {
-                if e.size > max {
-                    continue;
-                }
-            }
-            if let Some(min) = q.min_mtime {
-                if e.mtime < min {
-                    continue;
-                }
-            }
-            if let Some(max) = q.max_mtime {
-                if e.mtime > max {
-                    continue;
-                }
-            }
+    pub fn follow_symlinks_in_content(mut self, value: bool) -> Self {
+        self.query.follow_symlinks_in_content = value;
+        self
+    }
 
-            // root filter
-            if !q.roots.is_empty()
-                && !q
-                    .roots
-                    .iter()
-                    .any(|r| e.path.starts_with(r) || r == "/")
-            {
-                continue;
-            }
+    pub fn content_line_max_len(mut self, value: usize) -> Self {
+        self.query.content_line_max_len = value;
+        self
+    }
 
-            // exclude filter
-            for ex in &q.exclude {
-                if e.path.contains(ex) {
-                    continue 'outer;
-                }
-            }
+    pub fn match_filename_only(mut self, value: bool) -> Self {
+        self.query.match_filename_only = value;
+        self
+    }
 
-            if !matcher(&e.path) {
-                continue;
-            }
+    pub fn sparse_only(mut self, value: bool) -> Self {
+        self.query.sparse_only = value;
+        self
+    }
 
-            // content search (slow, optional)
-            if let Some(ref content_pattern) = q.content {
-                if e.is_dir {
-                    continue;
-                }
-                if !file_contains(&e.path, content_pattern) {
-                    continue;
-                }
-            }
+    pub fn executable_only(mut self, value: bool) -> Self {
+        self.query.executable_only = value;
+        self
+    }
 
-            out.push(e.clone());
-        }
+    pub fn modified_by_pid(mut self, value: u32) -> Self {
+        self.query.modified_by_pid = Some(value);
+        self
+    }
 
-        Ok(QueryResult { entries: out })
+    pub fn roots<S: Into<String>>(mut self, roots: impl IntoIterator<Item = S>) -> Self {
+        self.query.roots = roots.into_iter().map(Into::into).collect();
+        self
     }
-}
 
-fn build_matcher(q: &Query) -> Result<Box<dyn Fn(&str) -> bool + Send + Sync>> {
-    match q.mode {
-        PatternMode::Glob => {
-            let mut builder = GlobSetBuilder::new();
-            builder.add(Glob::new(&q.pattern)?);
-            let set: GlobSet = builder.build()?;
-            Ok(Box::new(move |path: &str| {
-                if let Some(fname) = Path::new(path).file_name().and_then(|s| s.to_str()) {
-                    set.is_match(fname)
-                } else {
-                    false
-                }
-            }))
-        }
-        PatternMode::Regex => {
-            let re = Regex::new(&q.pattern)?;
-            Ok(Box::new(move |path: &str| re.is_match(path)))
-        }
-        PatternMode::Substr => {
-            let needle = q.pattern.to_lowercase();
-            Ok(Box::new(move |path: &str| {
-                path.to_lowercase().contains(&needle)
-            }))
-        }
+    pub fn exclude<S: Into<String>>(mut self, exclude: impl IntoIterator<Item = S>) -> Self {
+        self.query.exclude = exclude.into_iter().map(Into::into).collect();
+        self
     }
-}
 
-fn file_contains(path: &str, needle: &str) -> bool {
-    // Simple, non-indexed content search (slow but optional)
-    if let Ok(text) = fs::read_to_string(path) {
-        text.contains(needle)
-    } else {
-        false
+    pub fn content(mut self, content: impl Into<String>) -> Self {
+        self.query.content = Some(content.into());
+        self
     }
-}
 
-fn should_skip(path: &Path, excludes: &[String]) -> bool {
-    const DEFAULT_SKIP: &[&str] = &[
-        "/proc", "/sys", "/dev", "/run", "/tmp", "/var/run", "/var/tmp", "/var/cache",
-        "/var/lib/snapd",
-    ];
-    let s = path.to_string_lossy();
+    /// Require this tag in addition to any added before.
+    pub fn has_tag(mut self, tag: impl Into<String>) -> Self {
+        self.query.has_tags.push(tag.into());
+        self
+    }
 
-    if DEFAULT_SKIP.iter().any(|p| s.starts_with(p)) {
-        return true;
+    pub fn regex_flags(mut self, flags: impl Into<String>) -> Self {
+        self.query.regex_flags = Some(flags.into());
+        self
     }
 
-    for ex in excludes {
-        if s.contains(ex) {
-            return true;
-        }
+    pub fn path_prefix_of(mut self, path: impl Into<String>) -> Self {
+        self.query.path_prefix_of = Some(path.into());
+        self
+    }
+
+    pub fn fingerprint_prefix(mut self, prefix: impl Into<Vec<u8>>) -> Self {
+        self.query.fingerprint_prefix = Some(prefix.into());
+        self
     }
-    false
-}
 
+    pub fn near_path(mut self, path: impl Into<String>) -> Self {
+        self.query.near_path = Some(path.into());
+        self
+    }
+
+    /// The query, or `LesError::InvalidQuery` listing every problem `validate` found.
+    pub fn build(self) -> Result<Query> {
+        self.query
+            .validate()
+            .map_err(|errors| LesError::InvalidQuery(errors.join("; ")))?;
+        Ok(self.query)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryResult {
+    pub entries: Vec<FileEntry>,
+    /// How many index entries were examined to find `entries`.
+    #[serde(default)]
+    pub scanned: usize,
+}
+
+impl QueryResult {
+    pub fn iter(&self) -> std::slice::Iter<'_, FileEntry> {
+        self.entries.iter()
+    }
+}
+
+impl IntoIterator for QueryResult {
+    type Item = FileEntry;
+    type IntoIter = std::vec::IntoIter<FileEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a QueryResult {
+    type Item = &'a FileEntry;
+    type IntoIter = std::slice::Iter<'a, FileEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+/// Summary of the index contents, as reported by `Request::Stats`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub entries: usize,
+    pub files: usize,
+    pub dirs: usize,
+    pub total_size: u64,
+    pub last_rebuilt: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub by_root: Vec<RootStats>,
+    /// Bytes used by the database on disk; `None` if it couldn't be measured.
+    #[serde(default)]
+    pub db_size: Option<u64>,
+    /// Paths watched for changes; see `Index::watch_count`.
+    #[serde(default)]
+    pub watch_count: usize,
+    /// Entries modified since the time the stats request asked about, if it did;
+    /// see `Index::recent_changes`.
+    #[serde(default)]
+    pub recent_changes: Option<usize>,
+}
+
+/// A change made to the index by the watcher, pushed to `lesd` subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEvent {
+    /// `updated` if `path` is indexed after the change, `removed` if it is not.
+    pub kind: String,
+    pub path: String,
+    /// The entry as now indexed; `None` for `removed`.
+    pub entry: Option<FileEntry>,
+}
+
+/// Per-root entry count and size, part of `IndexStats`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootStats {
+    pub root: String,
+    pub entries: usize,
+    pub total_size: u64,
+}
+
+/// File count and size for one extension, from `Index::extension_stats`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtensionStats {
+    /// Without the leading `.`; empty for files with no extension.
+    pub extension: String,
+    pub files: usize,
+    pub total_size: u64,
+}
+
+/// Outcome of `Index::rebuild`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RebuildStats {
+    pub entries_added: usize,
+    /// Directories pruned by the skip/exclude rules.
+    pub dirs_skipped: usize,
+    /// `(path, error message)` for entries that could not be read or stat'd.
+    pub errors: Vec<(String, String)>,
+    /// Number of entries (and roots) skipped because of errors; `errors.len()`.
+    #[serde(default)]
+    pub error_count: usize,
+    /// How many of `errors` were permission errors.
+    #[serde(default)]
+    pub permission_denied: usize,
+    pub duration: Duration,
+}
+
+/// Outcome of `Index::gc`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GcStats {
+    /// Entries dropped because their path no longer exists.
+    pub removed: usize,
+    pub duration: Duration,
+}
+
+/// Tuning knobs for `Index::open_with_config`.
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    /// sled page cache size in MiB.
+    pub sled_cache_mb: usize,
+    /// Maintain a trigram index to speed up substring queries. Costs several
+    /// times the memory of the entries themselves.
+    pub trigram_index: bool,
+    /// Keep file names in an ordered index so `PatternMode::Prefix` queries only
+    /// look at matching names. Costs a lowercased copy of every name; not persisted.
+    pub prefix_index: bool,
+    /// Stop walking a root once the estimated size of `Index::entries` passes this.
+    pub max_memory_mb: Option<usize>,
+    /// How values are encoded in sled. A database stored in another format is
+    /// converted when opened.
+    pub format: SerdeFormat,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            sled_cache_mb: 128,
+            trigram_index: false,
+            prefix_index: false,
+            max_memory_mb: None,
+            format: SerdeFormat::default(),
+        }
+    }
+}
+
+/// Encoding of the values `Index` stores in sled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SerdeFormat {
+    /// Compact and fastest, but not self-describing.
+    #[default]
+    Bincode,
+    /// MessagePack with field names: self-describing, and smaller than JSON.
+    MessagePack,
+    /// Human-readable, for inspecting the database while debugging; the largest.
+    Json,
+}
+
+impl SerdeFormat {
+    fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            SerdeFormat::Bincode => bincode::serialize(value)?,
+            SerdeFormat::MessagePack => rmp_serde::to_vec_named(value)?,
+            SerdeFormat::Json => serde_json::to_vec(value)?,
+        })
+    }
+
+    fn decode<T: serde::de::DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        Ok(match self {
+            SerdeFormat::Bincode => bincode::deserialize(bytes)?,
+            SerdeFormat::MessagePack => rmp_serde::from_slice(bytes)?,
+            SerdeFormat::Json => serde_json::from_slice(bytes)?,
+        })
+    }
+
+    /// Tag kept in the `meta` tree.
+    fn as_str(self) -> &'static str {
+        match self {
+            SerdeFormat::Bincode => "bincode",
+            SerdeFormat::MessagePack => "msgpack",
+            SerdeFormat::Json => "json",
+        }
+    }
+}
+
+impl std::str::FromStr for SerdeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bincode" => Ok(SerdeFormat::Bincode),
+            "msgpack" => Ok(SerdeFormat::MessagePack),
+            "json" => Ok(SerdeFormat::Json),
+            other => Err(anyhow::anyhow!("unknown storage format: {other}")),
+        }
+    }
+}
+
+/// Per-rebuild walk settings. Depths count path components below the root, which
+/// is depth 0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WalkOptions {
+    /// Excludes only apply to entries at least this deep; see `should_skip`.
+    pub exclude_min_depth: usize,
+    /// Don't index entries shallower than this (they are still walked through).
+    pub walk_min_depth: usize,
+    /// Don't descend below this depth.
+    pub walk_max_depth: Option<usize>,
+    /// Descend into symlinked directories. Directories reached twice (by `(dev, ino)`)
+    /// are indexed but not descended into again, which also breaks symlink cycles.
+    pub follow_symlinks: bool,
+    /// Read both ends of every file into `FileEntry::content_fingerprint`. Costs two
+    /// small reads per file and 129 bytes per entry.
+    pub fingerprint: bool,
+}
+
+/// How often (in entries) `index_root` checks `IndexConfig::max_memory_mb`.
+const MEMORY_CHECK_INTERVAL: usize = 10_000;
+
+/// Index abstraction: in-memory entries + sled DB on disk.
+///
+/// `entries` is public for reading; mutate it only through `Index` methods, which
+/// keep the lookup maps below in sync.
+///
+/// Cloning copies the in-memory entries and shares the sled database, so a clone
+/// suits read-only snapshots; changes made through one copy aren't seen by the other
+/// until it is reopened.
+#[derive(Clone)]
+pub struct Index {
+    pub entries: Vec<FileEntry>,
+    /// `(dev, ino)` -> position in `entries`.
+    by_inode: HashMap<(u64, u64), usize>,
+    /// Path -> position in `entries`, for O(1) `lookup_by_path`. If a path was
+    /// indexed twice (overlapping roots), this is the later one.
+    by_path: HashMap<String, usize>,
+    /// File extension -> positions in `entries` of the files with it.
+    by_extension: HashMap<String, Vec<usize>>,
+    /// `(mtime, position in entries)` for every entry, sorted, so entries modified
+    /// after a given time form a suffix.
+    by_mtime: Vec<(i64, usize)>,
+    /// Present when `IndexConfig::trigram_index` is set.
+    trigrams: Option<TrigramIndex>,
+    /// Present when `IndexConfig::prefix_index` is set; rebuilt with the lookup maps.
+    prefixes: Option<PrefixIndex>,
+    /// Whether the `trigrams` tree in sled matches the in-memory index.
+    trigrams_persisted: bool,
+    /// Absolute roots of the last rebuild; `FileEntry::root_idx` points into this.
+    roots: Vec<String>,
+    /// Excludes of the last rebuild; `None` for indexes built before they were stored.
+    excludes: Option<Vec<String>>,
+    last_rebuilt: Option<DateTime<Local>>,
+    /// Entries skipped because of errors during the last rebuild in this process.
+    error_count: usize,
+    /// From `IndexConfig::max_memory_mb`, in bytes.
+    max_memory_bytes: Option<usize>,
+    /// Successful `watch` calls minus `unwatch` calls, kept by whoever runs the
+    /// watcher (see `watch_counter`).
+    watch_count: Arc<AtomicUsize>,
+    format: SerdeFormat,
+    db: Db,
+    db_path: PathBuf,
+}
+
+const META_LAST_REBUILT: &[u8] = b"last_rebuilt";
+const META_SCHEMA_VERSION: &[u8] = b"schema_version";
+const META_ROOTS: &[u8] = b"roots";
+const META_EXCLUDES: &[u8] = b"excludes";
+/// Set to 1 while the `trigrams` tree matches `entries` in sled key order.
+const META_TRIGRAMS_VALID: &[u8] = b"trigrams_valid";
+/// `SerdeFormat::as_str` of the values in every tree; absent means bincode.
+const META_FORMAT: &[u8] = b"format";
+
+/// Version of the serialized `FileEntry` layout in the `entries` tree. Bump it whenever
+/// `FileEntry` changes shape so stale databases are discarded instead of failing to decode.
+const SCHEMA_VERSION: u32 = 8;
+
+/// Re-encode the values in `db` from `from` to `to`. Cached hashes are dropped and
+/// stored trigrams invalidated rather than converted, since both are rebuilt on demand.
+fn convert_format(db: &Db, from: SerdeFormat, to: SerdeFormat) -> Result<()> {
+    tracing::info!(from = from.as_str(), to = to.as_str(), "converting index storage format");
+    let entries = db.open_tree("entries")?;
+    for item in entries.iter() {
+        let (k, v) = item?;
+        let entry: FileEntry = from.decode(&v)?;
+        entries.insert(k, to.encode(&entry)?)?;
+    }
+    let tags = db.open_tree("tags")?;
+    for item in tags.iter() {
+        let (k, v) = item?;
+        let list: Vec<String> = from.decode(&v)?;
+        tags.insert(k, to.encode(&list)?)?;
+    }
+    db.open_tree("hashes")?.clear()?;
+
+    let meta = db.open_tree("meta")?;
+    for key in [META_ROOTS, META_EXCLUDES] {
+        if let Some(v) = meta.get(key)? {
+            let list: Vec<String> = from.decode(&v)?;
+            meta.insert(key, to.encode(&list)?)?;
+        }
+    }
+    meta.insert(META_TRIGRAMS_VALID, &[0u8])?;
+    db.flush()?;
+    Ok(())
+}
+
+impl Index {
+    /// Open or create index DB and load entries into memory
+    pub fn open(db_path: &Path) -> Result<Self> {
+        Self::open_with_config(db_path, IndexConfig::default())
+    }
+
+    /// Like `open`, with explicit sled settings.
+    pub fn open_with_config(db_path: &Path, config: IndexConfig) -> Result<Self> {
+        let db = sled::Config::new()
+            .path(db_path)
+            .cache_capacity((config.sled_cache_mb as u64) * 1024 * 1024)
+            .open()
+            .map_err(|e| match e {
+                // sled holds an flock on `<db_path>/db`; the kernel drops it when the
+                // holder exits, so this always means a live process has it open.
+                sled::Error::Io(ref io) if io.to_string().contains("could not acquire lock") => {
+                    anyhow::Error::new(LesError::DatabaseLocked(db_path.display().to_string()))
+                }
+                e => e.into(),
+            })?;
+        Self::from_db(db, db_path.to_path_buf(), config)
+    }
+
+    /// An empty index in a temporary sled database that is deleted when the index is
+    /// dropped, for one-shot use where nothing should outlive the process.
+    pub fn open_memory() -> Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let db_path = std::env::temp_dir().join(format!(
+            "les-memory-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let config = IndexConfig::default();
+        let db = sled::Config::new()
+            .path(&db_path)
+            .temporary(true)
+            .cache_capacity((config.sled_cache_mb as u64) * 1024 * 1024)
+            .open()?;
+        Self::from_db(db, db_path, config)
+    }
+
+    /// Load entries and metadata from an already opened `db`.
+    fn from_db(db: Db, db_path: PathBuf, config: IndexConfig) -> Result<Self> {
+        let tree = db.open_tree("entries")?;
+        let meta = db.open_tree("meta")?;
+        let mut entries = Vec::new();
+
+        let version = match meta.get(META_SCHEMA_VERSION)? {
+            Some(v) => u32::from_be_bytes(<[u8; 4]>::try_from(v.as_ref())?),
+            None => 1,
+        };
+        if version != SCHEMA_VERSION && !tree.is_empty() {
+            tracing::warn!(
+                found = version,
+                expected = SCHEMA_VERSION,
+                "index format changed, discarding stored entries"
+            );
+            tree.clear()?;
+        }
+        meta.insert(META_SCHEMA_VERSION, &SCHEMA_VERSION.to_be_bytes())?;
+
+        let format = config.format;
+        let stored = match meta.get(META_FORMAT)? {
+            Some(v) => std::str::from_utf8(&v)?.parse()?,
+            None => SerdeFormat::Bincode,
+        };
+        if stored != format {
+            convert_format(&db, stored, format)?;
+        }
+        meta.insert(META_FORMAT, format.as_str())?;
+
+        for item in tree.iter() {
+            let (_, v) = item?;
+            let e: FileEntry = format.decode(&v)?;
+            entries.push(e);
+        }
+
+        let last_rebuilt = match meta.get(META_LAST_REBUILT)? {
+            Some(v) => {
+                let secs = i64::from_be_bytes(<[u8; 8]>::try_from(v.as_ref())?);
+                DateTime::from_timestamp(secs, 0).map(|dt| dt.with_timezone(&Local))
+            }
+            None => None,
+        };
+        let roots = match meta.get(META_ROOTS)? {
+            Some(v) => format.decode(&v)?,
+            None => Vec::new(),
+        };
+        let excludes = match meta.get(META_EXCLUDES)? {
+            Some(v) => Some(format.decode(&v)?),
+            None => None,
+        };
+
+        let mut index = Self {
+            entries,
+            by_inode: HashMap::new(),
+            by_extension: HashMap::new(),
+            by_mtime: Vec::new(),
+            by_path: HashMap::new(),
+            trigrams: None,
+            trigrams_persisted: false,
+            prefixes: config.prefix_index.then(PrefixIndex::default),
+            roots,
+            excludes,
+            last_rebuilt,
+            error_count: 0,
+            max_memory_bytes: config.max_memory_mb.map(|mb| mb * 1024 * 1024),
+            watch_count: Arc::default(),
+            format,
+            db,
+            db_path,
+        };
+        index.rebuild_lookups();
+
+        if config.trigram_index {
+            // Postings are positions in sled key order, which is the order `entries`
+            // was just loaded in; they're only reusable if nothing moved since `save`.
+            let valid = meta.get(META_TRIGRAMS_VALID)?.as_deref() == Some(&[1u8][..]);
+            if valid {
+                let tree = index.db.open_tree("trigrams")?;
+                index.trigrams = Some(TrigramIndex::load(&tree, index.format)?);
+                index.trigrams_persisted = true;
+            } else {
+                index.rebuild_trigrams()?;
+            }
+        }
+        Ok(index)
+    }
+
+    /// Rebuild the trigram index from `entries` and persist it. Callers must make
+    /// sure `entries` is in path order so positions match sled key order.
+    fn rebuild_trigrams(&mut self) -> Result<()> {
+        let trigrams = TrigramIndex::build(self.entries.iter().map(|e| e.path.as_str()));
+        trigrams.save(&self.db.open_tree("trigrams")?, self.format)?;
+        let meta = self.db.open_tree("meta")?;
+        meta.insert(META_TRIGRAMS_VALID, &[1u8])?;
+        meta.flush()?;
+        self.trigrams = Some(trigrams);
+        self.trigrams_persisted = true;
+        Ok(())
+    }
+
+    /// After an in-place change, the persisted postings no longer line up with sled.
+    fn invalidate_persisted_trigrams(&mut self) {
+        if !self.trigrams_persisted {
+            return;
+        }
+        if let Ok(meta) = self.db.open_tree("meta") {
+            let _ = meta.insert(META_TRIGRAMS_VALID, &[0u8]);
+        }
+        self.trigrams_persisted = false;
+    }
+
+    /// Recompute the lookup maps from `entries` after a bulk change.
+    fn rebuild_lookups(&mut self) {
+        self.by_inode.clear();
+        self.by_path.clear();
+        self.by_extension.clear();
+        if let Some(prefixes) = &mut self.prefixes {
+            *prefixes = PrefixIndex::build(self.entries.iter().map(|e| e.path.as_str()));
+        }
+        self.by_mtime = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (e.mtime, i))
+            .collect();
+        self.by_mtime.sort_unstable();
+        for (i, e) in self.entries.iter().enumerate() {
+            self.by_inode.insert((e.dev, e.ino), i);
+            self.by_path.insert(e.path.clone(), i);
+            if let Some(ext) = e.extension() {
+                self.by_extension
+                    .entry(ext.to_string())
+                    .or_default()
+                    .push(i);
+            }
+        }
+    }
+
+    /// Append an entry, keeping the lookup maps in sync. Does not touch sled.
+    fn insert_entry(&mut self, entry: FileEntry) {
+        let pos = self.entries.len();
+        self.by_inode.insert((entry.dev, entry.ino), pos);
+        self.by_path.insert(entry.path.clone(), pos);
+        if let Some(ext) = entry.extension() {
+            self.by_extension
+                .entry(ext.to_string())
+                .or_default()
+                .push(pos);
+        }
+        if let Some(trigrams) = &mut self.trigrams {
+            trigrams.add(pos, &entry.path);
+            self.invalidate_persisted_trigrams();
+        }
+        if let Some(prefixes) = &mut self.prefixes {
+            prefixes.add(pos, &entry.path);
+        }
+        self.add_mtime(entry.mtime, pos);
+        self.entries.push(entry);
+    }
+
+    /// Remove every in-memory entry for `path`, keeping the lookup maps in sync.
+    /// Does not touch sled.
+    fn remove_entries(&mut self, path: &str) {
+        // Most watcher events are for new paths; skip the scan for those.
+        if self.by_path.remove(path).is_none() {
+            return;
+        }
+        while let Some(pos) = self.entries.iter().position(|e| e.path == path) {
+            let last = self.entries.len() - 1;
+            let removed = self.entries.swap_remove(pos);
+            if self.by_inode.get(&(removed.dev, removed.ino)) == Some(&pos) {
+                self.by_inode.remove(&(removed.dev, removed.ino));
+            }
+            if let Some(ext) = removed.extension() {
+                if let Some(list) = self.by_extension.get_mut(ext) {
+                    list.retain(|&i| i != pos);
+                    if list.is_empty() {
+                        self.by_extension.remove(ext);
+                    }
+                }
+            }
+            if let Some(trigrams) = &mut self.trigrams {
+                trigrams.remove(pos, &removed.path);
+            }
+            if let Some(prefixes) = &mut self.prefixes {
+                prefixes.remove(pos, &removed.path);
+            }
+            self.remove_mtime(removed.mtime, pos);
+            // The former last entry now lives at `pos`.
+            if let Some(mtime) = self.entries.get(pos).map(|e| e.mtime) {
+                self.remove_mtime(mtime, last);
+                self.add_mtime(mtime, pos);
+            }
+            if let Some(moved) = self.entries.get(pos) {
+                self.by_inode.insert((moved.dev, moved.ino), pos);
+                if let Some(i) = self.by_path.get_mut(&moved.path) {
+                    if *i == last {
+                        *i = pos;
+                    }
+                }
+                if let Some(list) = moved
+                    .extension()
+                    .and_then(|ext| self.by_extension.get_mut(ext))
+                {
+                    for i in list.iter_mut().filter(|i| **i == last) {
+                        *i = pos;
+                    }
+                }
+                if let Some(trigrams) = &mut self.trigrams {
+                    trigrams.remove(last, &moved.path);
+                    trigrams.add(pos, &moved.path);
+                }
+                if let Some(prefixes) = &mut self.prefixes {
+                    prefixes.remove(last, &moved.path);
+                    prefixes.add(pos, &moved.path);
+                }
+            }
+            if self.trigrams.is_some() {
+                self.invalidate_persisted_trigrams();
+            }
+        }
+    }
+
+    fn add_mtime(&mut self, mtime: i64, pos: usize) {
+        let at = self.by_mtime.partition_point(|&k| k < (mtime, pos));
+        self.by_mtime.insert(at, (mtime, pos));
+    }
+
+    fn remove_mtime(&mut self, mtime: i64, pos: usize) {
+        if let Ok(at) = self.by_mtime.binary_search(&(mtime, pos)) {
+            self.by_mtime.remove(at);
+        }
+    }
+
+    /// Whether `path` is in the index.
+    pub fn path_exists(&self, path: &str) -> bool {
+        self.by_path.contains_key(path)
+    }
+
+    /// The indexed entry for `path`, from a map kept alongside `entries` rather
+    /// than a scan. `None` if `path` isn't indexed.
+    pub fn lookup_by_path(&self, path: &str) -> Option<&FileEntry> {
+        self.by_path.get(path).map(|&i| &self.entries[i])
+    }
+
+    /// Entry with the given device and inode numbers, if indexed. For hard links
+    /// this is whichever of the linked paths was indexed last.
+    pub fn find_by_inode(&self, dev: u64, ino: u64) -> Option<&FileEntry> {
+        self.by_inode.get(&(dev, ino)).map(|&i| &self.entries[i])
+    }
+
+    /// Files whose extension is exactly `ext` (case-sensitive, leading `.` optional),
+    /// from a map kept alongside `entries` rather than a scan.
+    pub fn by_extension<'a>(&'a self, ext: &str) -> impl Iterator<Item = &'a FileEntry> {
+        let ext = ext.strip_prefix('.').unwrap_or(ext);
+        self.by_extension
+            .get(ext)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.entries[i])
+    }
+
+    /// Entries with `mtime >= since` (UNIX epoch seconds), oldest first. Found by
+    /// binary search in an mtime-sorted list kept alongside `entries`, not a scan.
+    pub fn recent_changes(&self, since: i64) -> impl Iterator<Item = &FileEntry> {
+        let start = self.by_mtime.partition_point(|&(mtime, _)| mtime < since);
+        self.by_mtime[start..]
+            .iter()
+            .map(|&(_, i)| &self.entries[i])
+    }
+
+    /// The entry with the smallest mtime, if any.
+    pub fn oldest_entry(&self) -> Option<&FileEntry> {
+        self.by_mtime.first().map(|&(_, i)| &self.entries[i])
+    }
+
+    /// The entry with the largest mtime, if any.
+    pub fn newest_entry(&self) -> Option<&FileEntry> {
+        self.by_mtime.last().map(|&(_, i)| &self.entries[i])
+    }
+
+    /// Entries whose parent directory is exactly `dir`, without descending into
+    /// subdirectories. Scans `entries`; there is no per-directory map.
+    pub fn entries_in_dir<'a>(&'a self, dir: &'a str) -> impl Iterator<Item = &'a FileEntry> {
+        let dir = Path::new(dir);
+        self.entries
+            .iter()
+            .filter(move |e| Path::new(&e.path).parent() == Some(dir))
+    }
+
+    /// Absolute roots used by the last rebuild.
+    pub fn last_roots(&self) -> &[String] {
+        &self.roots
+    }
+
+    /// Exclude patterns used by the last rebuild, if recorded.
+    pub fn last_excludes(&self) -> Option<&[String]> {
+        self.excludes.as_deref()
+    }
+
+    /// Time of the last successful `rebuild`, if any.
+    pub fn last_rebuilt(&self) -> Option<DateTime<Local>> {
+        self.last_rebuilt
+    }
+
+    /// Entries skipped because of errors during the last `rebuild`; 0 if there
+    /// hasn't been one since `open`.
+    pub fn error_count(&self) -> usize {
+        self.error_count
+    }
+
+    /// Number of paths currently watched for changes, as reported by the watcher.
+    /// With recursive watches this counts roots, not the kernel watches under them.
+    pub fn watch_count(&self) -> usize {
+        self.watch_count.load(Ordering::Relaxed)
+    }
+
+    /// Shared counter behind `watch_count`, for a watcher thread to update without
+    /// holding the index lock.
+    pub fn watch_counter(&self) -> Arc<AtomicUsize> {
+        self.watch_count.clone()
+    }
+
+    /// Directory holding the sled database.
+    pub fn db_path(&self) -> &Path {
+        &self.db_path
+    }
+
+    /// Total size in bytes of the files under `db_path`.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        db_size_on_disk(&self.db_path)
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        let dirs = self.entries.iter().filter(|e| e.is_dir).count();
+        IndexStats {
+            entries: self.entries.len(),
+            files: self.entries.len() - dirs,
+            dirs,
+            total_size: self.entries.iter().map(|e| e.size).sum(),
+            last_rebuilt: self.last_rebuilt,
+            by_root: self.root_stats(),
+            db_size: self.size_on_disk().ok(),
+            watch_count: self.watch_count(),
+            recent_changes: None,
+        }
+    }
+
+    /// File count and total size per extension, most common first, from the
+    /// extension map rather than a scan. Files without an extension are counted
+    /// under `""`; directories are left out.
+    pub fn extension_stats(&self) -> Vec<ExtensionStats> {
+        let mut out: Vec<ExtensionStats> = self
+            .by_extension
+            .iter()
+            .map(|(ext, positions)| ExtensionStats {
+                extension: ext.clone(),
+                files: positions.len(),
+                total_size: positions.iter().map(|&i| self.entries[i].size).sum(),
+            })
+            .collect();
+        let files = self.entries.iter().filter(|e| !e.is_dir).count();
+        let with_ext: usize = out.iter().map(|s| s.files).sum();
+        if files > with_ext {
+            out.push(ExtensionStats {
+                extension: String::new(),
+                files: files - with_ext,
+                total_size: self
+                    .entries
+                    .iter()
+                    .filter(|e| !e.is_dir && e.extension().is_none())
+                    .map(|e| e.size)
+                    .sum(),
+            });
+        }
+        out.sort_by(|a, b| b.files.cmp(&a.files).then_with(|| a.extension.cmp(&b.extension)));
+        out
+    }
+
+    fn root_stats(&self) -> Vec<RootStats> {
+        let mut out: Vec<RootStats> = self
+            .roots
+            .iter()
+            .map(|r| RootStats {
+                root: r.clone(),
+                entries: 0,
+                total_size: 0,
+            })
+            .collect();
+        for e in &self.entries {
+            if let Some(rs) = out.get_mut(usize::from(e.root_idx)) {
+                rs.entries += 1;
+                rs.total_size += e.size;
+            }
+        }
+        out
+    }
+
+    /// Rebuild index from scratch for given roots.
+    pub fn rebuild(
+        &mut self,
+        roots: &[String],
+        excludes: &[String],
+        walk: WalkOptions,
+    ) -> Result<RebuildStats> {
+        let started = Instant::now();
+        let mut stats = RebuildStats::default();
+        let roots = self.walk_roots(roots, excludes, walk, &mut stats)?;
+
+        // Persist entries into DB
+        let tree = self.db.open_tree("entries")?;
+        tree.clear()?;
+        for entry in &self.entries {
+            let key = entry.path.as_bytes();
+            let val = self.format.encode(entry)?;
+            tree.insert(key, val)?;
+        }
+        tree.flush()?;
+
+        self.finish_rebuild(roots, excludes, started, &mut stats)?;
+        Ok(stats)
+    }
+
+    /// `rebuild` on Tokio's blocking thread pool, so an async caller doesn't stall
+    /// its runtime. Must be awaited inside a Tokio runtime.
+    ///
+    /// The index is moved to the blocking task and back. If the future is dropped
+    /// before it completes, the rebuild still runs to the end and is saved to sled,
+    /// but `self` is left empty until reopened.
+    #[cfg(feature = "tokio")]
+    pub async fn rebuild_async(
+        &mut self,
+        roots: Vec<String>,
+        excludes: Vec<String>,
+        walk: WalkOptions,
+    ) -> Result<RebuildStats> {
+        let mut index = Self {
+            entries: std::mem::take(&mut self.entries),
+            by_inode: std::mem::take(&mut self.by_inode),
+            by_path: std::mem::take(&mut self.by_path),
+            by_extension: std::mem::take(&mut self.by_extension),
+            by_mtime: std::mem::take(&mut self.by_mtime),
+            trigrams: self.trigrams.take(),
+            trigrams_persisted: self.trigrams_persisted,
+            prefixes: self.prefixes.take(),
+            roots: std::mem::take(&mut self.roots),
+            excludes: self.excludes.take(),
+            last_rebuilt: self.last_rebuilt,
+            error_count: self.error_count,
+            max_memory_bytes: self.max_memory_bytes,
+            watch_count: self.watch_count.clone(),
+            format: self.format,
+            db: self.db.clone(),
+            db_path: self.db_path.clone(),
+        };
+        let (index, stats) = tokio::task::spawn_blocking(move || {
+            let stats = index.rebuild(&roots, &excludes, walk);
+            (index, stats)
+        })
+        .await?;
+        *self = index;
+        stats
+    }
+
+    /// Like `rebuild`, but only rewrites entries whose mtime, size or root changed
+    /// since they were last indexed, and deletes the ones that disappeared.
+    ///
+    /// Every path is still walked and stat'd; what this saves is the sled writes,
+    /// which dominate a full rebuild on a mostly unchanged tree.
+    pub fn rebuild_incremental(
+        &mut self,
+        roots: &[String],
+        excludes: &[String],
+        walk: WalkOptions,
+    ) -> Result<RebuildStats> {
+        let started = Instant::now();
+        let mut stats = RebuildStats::default();
+        let mut stored: HashMap<String, (i64, u64, u16)> = self
+            .entries
+            .drain(..)
+            .map(|e| (e.path, (e.mtime, e.size, e.root_idx)))
+            .collect();
+        let roots = self.walk_roots(roots, excludes, walk, &mut stats)?;
+
+        let tree = self.db.open_tree("entries")?;
+        let mut written = 0;
+        for entry in &self.entries {
+            let unchanged = stored.remove(&entry.path)
+                == Some((entry.mtime, entry.size, entry.root_idx));
+            if !unchanged {
+                tree.insert(entry.path.as_bytes(), self.format.encode(entry)?)?;
+                written += 1;
+            }
+        }
+        for path in stored.keys() {
+            tree.remove(path.as_bytes())?;
+        }
+        tree.flush()?;
+        tracing::info!(written, removed = stored.len(), "incremental rebuild");
+
+        self.finish_rebuild(roots, excludes, started, &mut stats)?;
+        Ok(stats)
+    }
+
+    /// Re-walk a single root, replacing the entries under it and leaving other roots'
+    /// entries alone. `root` must be one of the roots of the last rebuild or is added
+    /// as a new one. Returns the number of entries indexed under it.
+    pub fn rebuild_root_only(
+        &mut self,
+        root: &str,
+        excludes: &[String],
+        walk: WalkOptions,
+    ) -> Result<usize> {
+        let root = std::path::absolute(root)?.to_string_lossy().to_string();
+        let root_idx = match self.roots.iter().position(|r| *r == root) {
+            Some(i) => i,
+            None => {
+                if self.roots.len() > usize::from(u16::MAX) {
+                    anyhow::bail!("too many roots: at most 65536");
+                }
+                self.roots.push(root.clone());
+                let meta = self.db.open_tree("meta")?;
+                meta.insert(META_ROOTS, self.format.encode(&self.roots)?)?;
+                meta.flush()?;
+                self.roots.len() - 1
+            }
+        };
+
+        // Component-wise, so rebuilding `/home` leaves `/homework` alone.
+        let under_root = |path: &str| Path::new(path).starts_with(&root);
+        let tree = self.db.open_tree("entries")?;
+        for key in tree.scan_prefix(root.as_bytes()).keys() {
+            let key = key?;
+            if under_root(&String::from_utf8_lossy(&key)) {
+                tree.remove(key)?;
+            }
+        }
+        self.entries.retain(|e| !under_root(&e.path));
+
+        let exclude_set = build_exclude_set(excludes)?;
+        let mut stats = RebuildStats::default();
+        let before = self.entries.len();
+        // Bounded by the roots check above.
+        self.index_root(
+            Path::new(&root),
+            root_idx as u16,
+            exclude_set.as_ref(),
+            walk,
+            &mut stats,
+        )?;
+
+        let tags = self.db.open_tree("tags")?;
+        for entry in &mut self.entries[before..] {
+            if let Some(v) = tags.get(entry.path.as_bytes())? {
+                entry.tags = self.format.decode(&v)?;
+            }
+            tree.insert(entry.path.as_bytes(), self.format.encode(&*entry)?)?;
+        }
+        tree.flush()?;
+        let added = self.entries.len() - before;
+
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self.rebuild_lookups();
+        if self.trigrams.is_some() {
+            self.rebuild_trigrams()?;
+        }
+        tracing::info!(%root, added, errors = stats.errors.len(), "rebuilt root");
+        Ok(added)
+    }
+
+    /// Drop `root` from the roots of the last rebuild along with every entry found
+    /// under it. Later roots move down one place. Returns the number of entries removed.
+    pub fn remove_root(&mut self, root: &str) -> Result<usize> {
+        let root = std::path::absolute(root)?.to_string_lossy().to_string();
+        let Some(root_idx) = self.roots.iter().position(|r| *r == root) else {
+            return Err(LesError::NotIndexed(root).into());
+        };
+        // Bounded by the roots check in `walk_roots` / `rebuild_root_only`.
+        let root_idx = root_idx as u16;
+
+        let tree = self.db.open_tree("entries")?;
+        let before = self.entries.len();
+        let mut kept = Vec::with_capacity(before);
+        for mut entry in std::mem::take(&mut self.entries) {
+            if entry.root_idx == root_idx {
+                tree.remove(entry.path.as_bytes())?;
+                continue;
+            }
+            if entry.root_idx > root_idx {
+                entry.root_idx -= 1;
+                tree.insert(entry.path.as_bytes(), self.format.encode(&entry)?)?;
+            }
+            kept.push(entry);
+        }
+        tree.flush()?;
+        self.entries = kept;
+        let removed = before - self.entries.len();
+
+        self.roots.remove(usize::from(root_idx));
+        let meta = self.db.open_tree("meta")?;
+        meta.insert(META_ROOTS, self.format.encode(&self.roots)?)?;
+        meta.flush()?;
+
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self.rebuild_lookups();
+        if self.trigrams.is_some() {
+            self.rebuild_trigrams()?;
+        }
+        tracing::info!(%root, removed, "removed root");
+        Ok(removed)
+    }
+
+    /// Remove every entry whose path no longer exists, e.g. after the daemon was
+    /// down while files were deleted. Stats each entry; nothing is walked.
+    pub fn gc(&mut self) -> Result<GcStats> {
+        let started = Instant::now();
+        let (kept, stale): (Vec<FileEntry>, Vec<FileEntry>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|e| Path::new(&e.path).exists());
+        self.entries = kept;
+        if stale.is_empty() {
+            return Ok(GcStats {
+                removed: 0,
+                duration: started.elapsed(),
+            });
+        }
+
+        let tree = self.db.open_tree("entries")?;
+        for entry in &stale {
+            tree.remove(entry.path.as_bytes())?;
+            self.invalidate_hash(&entry.path, None);
+        }
+        tree.flush()?;
+
+        // Watcher updates leave `entries` out of order; stored trigrams need path order.
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self.rebuild_lookups();
+        if self.trigrams.is_some() {
+            self.rebuild_trigrams()?;
+        }
+        let stats = GcStats {
+            removed: stale.len(),
+            duration: started.elapsed(),
+        };
+        tracing::info!(removed = stats.removed, "removed stale entries");
+        Ok(stats)
+    }
+
+    /// Walk `roots` into a fresh, path-sorted `self.entries` with stored tags
+    /// applied. Returns the roots made absolute.
+    fn walk_roots(
+        &mut self,
+        roots: &[String],
+        excludes: &[String],
+        walk: WalkOptions,
+        stats: &mut RebuildStats,
+    ) -> Result<Vec<String>> {
+        if roots.len() > usize::from(u16::MAX) + 1 {
+            anyhow::bail!("too many roots: {} (at most 65536)", roots.len());
+        }
+        let roots = roots
+            .iter()
+            .map(|r| Ok(std::path::absolute(r)?.to_string_lossy().to_string()))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Compile once; `should_skip` runs for every entry of the walk.
+        let exclude_set = build_exclude_set(excludes)?;
+
+        self.entries.clear();
+        for (i, root) in roots.iter().enumerate() {
+            // Bounded by the roots.len() check above.
+            let root_idx = i as u16;
+            self.index_root(
+                Path::new(root),
+                root_idx,
+                exclude_set.as_ref(),
+                walk,
+                stats,
+            )?;
+        }
+        stats.entries_added = self.entries.len();
+
+        // Tags live in their own tree so they survive rebuilds.
+        let tags = self.db.open_tree("tags")?;
+        for entry in &mut self.entries {
+            if let Some(v) = tags.get(entry.path.as_bytes())? {
+                entry.tags = self.format.decode(&v)?;
+            }
+        }
+
+        // Keep memory in sled key order so position-based trigram postings survive a reopen.
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(roots)
+    }
+
+    /// Shared tail of `rebuild` and `rebuild_incremental`, once entries are persisted.
+    fn finish_rebuild(
+        &mut self,
+        roots: Vec<String>,
+        excludes: &[String],
+        started: Instant,
+        stats: &mut RebuildStats,
+    ) -> Result<()> {
+        self.rebuild_lookups();
+        if self.trigrams.is_some() {
+            self.rebuild_trigrams()?;
+        }
+        // Cached hashes of paths that are gone would otherwise accumulate forever.
+        let hashes = self.db.open_tree("hashes")?;
+        for key in hashes.iter().keys() {
+            let key = key?;
+            if !self.path_exists(&String::from_utf8_lossy(&key)) {
+                hashes.remove(key)?;
+            }
+        }
+
+        let now = Local::now();
+        let meta = self.db.open_tree("meta")?;
+        meta.insert(META_LAST_REBUILT, &now.timestamp().to_be_bytes())?;
+        meta.insert(META_ROOTS, self.format.encode(&roots)?)?;
+        meta.insert(META_EXCLUDES, self.format.encode(excludes)?)?;
+        meta.flush()?;
+        self.last_rebuilt = Some(now);
+        self.roots = roots;
+        self.excludes = Some(excludes.to_vec());
+
+        stats.error_count = stats.errors.len();
+        self.error_count = stats.error_count;
+        if stats.permission_denied > 0 {
+            tracing::warn!(
+                "{} paths skipped due to permission errors",
+                stats.permission_denied
+            );
+        }
+        if stats.error_count > 0 {
+            tracing::warn!("{} entries skipped due to errors", stats.error_count);
+        }
+
+        stats.duration = started.elapsed();
+        Ok(())
+    }
+
+    fn index_root(
+        &mut self,
+        root: &Path,
+        root_idx: u16,
+        excludes: Option<&ExcludeSet>,
+        walk: WalkOptions,
+        stats: &mut RebuildStats,
+    ) -> Result<()> {
+        let root = std::path::absolute(root)?;
+        if should_skip(&root, &root, excludes, walk.exclude_min_depth) {
+            stats.dirs_skipped += 1;
+            return Ok(());
+        }
+
+        // A missing or unreadable root would otherwise just yield zero entries.
+        let root_str = root.to_string_lossy().to_string();
+        if !root.exists() {
+            tracing::warn!(root = %root_str, "root does not exist");
+            stats.errors.push((root_str, "root does not exist".to_string()));
+            return Ok(());
+        }
+        let readable = fs::metadata(&root).and_then(|md| {
+            if md.is_dir() {
+                fs::read_dir(&root).map(drop)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(err) = readable {
+            if err.kind() == std::io::ErrorKind::PermissionDenied {
+                tracing::warn!(root = %root_str, "root not accessible");
+                stats.permission_denied += 1;
+                stats.errors.push((root_str, format!("root not accessible: {err}")));
+                return Ok(());
+            }
+        }
+
+        let dirs_skipped = Arc::new(AtomicUsize::new(0));
+        // Running estimate for the memory cap: struct sizes plus path bytes.
+        let mut path_bytes: usize = self.entries.iter().map(|e| e.path.len()).sum();
+        for e in walk_root(&root, excludes, walk, dirs_skipped.clone()) {
+            if let Some(limit) = self.max_memory_bytes {
+                if self.entries.len().is_multiple_of(MEMORY_CHECK_INTERVAL) {
+                    let estimate =
+                        self.entries.len() * std::mem::size_of::<FileEntry>() + path_bytes;
+                    if estimate > limit {
+                        tracing::warn!(
+                            root = %root_str,
+                            entries = self.entries.len(),
+                            "memory limit reached, not indexing the rest of this root"
+                        );
+                        stats.errors.push((root_str, "memory limit reached".to_string()));
+                        break;
+                    }
+                }
+            }
+
+            let mut entry = match e {
+                Ok(v) => v,
+                Err((path, err)) => {
+                    tracing::debug!(%path, error = %err, "skipping unreadable entry");
+                    if err.kind() == std::io::ErrorKind::PermissionDenied {
+                        stats.permission_denied += 1;
+                    }
+                    stats.errors.push((path, err.to_string()));
+                    continue;
+                }
+            };
+            entry.root_idx = root_idx;
+            // Roots are made absolute above, so this only fires on a walker bug.
+            if let Err(err) = validate_entry_path(&entry.path) {
+                stats.errors.push((entry.path, err.to_string()));
+                continue;
+            }
+            path_bytes += entry.path.len();
+            self.entries.push(entry);
+        }
+        stats.dirs_skipped += dirs_skipped.load(Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Insert (or replace) a single entry and persist it.
+    pub fn add_entry(&mut self, entry: FileEntry) -> Result<()> {
+        validate_entry_path(&entry.path)?;
+
+        let tree = self.db.open_tree("entries")?;
+        tree.insert(entry.path.as_bytes(), self.format.encode(&entry)?)?;
+        tree.flush()?;
+
+        self.remove_entries(&entry.path);
+        self.insert_entry(entry);
+        Ok(())
+    }
+
+    /// Like `add_entry` for many entries at once, with a single sled flush.
+    pub fn add_entries(&mut self, entries: impl IntoIterator<Item = FileEntry>) -> Result<()> {
+        let tree = self.db.open_tree("entries")?;
+        let mut batch = sled::Batch::default();
+        let mut added = Vec::new();
+        for entry in entries {
+            validate_entry_path(&entry.path)?;
+            batch.insert(entry.path.as_bytes(), self.format.encode(&entry)?);
+            added.push(entry);
+        }
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+
+        for entry in added {
+            self.remove_entries(&entry.path);
+            self.insert_entry(entry);
+        }
+        Ok(())
+    }
+
+    /// Replace the tags on an indexed entry. Tags are kept across rebuilds.
+    pub fn tag_entry(&mut self, path: &str, tags: Vec<String>) -> Result<()> {
+        let pos = *self
+            .by_path
+            .get(path)
+            .ok_or_else(|| LesError::NotIndexed(path.to_string()))?;
+
+        let tag_tree = self.db.open_tree("tags")?;
+        if tags.is_empty() {
+            tag_tree.remove(path.as_bytes())?;
+        } else {
+            tag_tree.insert(path.as_bytes(), self.format.encode(&tags)?)?;
+        }
+        tag_tree.flush()?;
+
+        let entry = &mut self.entries[pos];
+        entry.tags = tags;
+        let tree = self.db.open_tree("entries")?;
+        tree.insert(path.as_bytes(), self.format.encode(&*entry)?)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Set the indexed mtime of `path` without re-reading anything else, for
+    /// metadata-only changes such as `touch`. Returns `false` if `path` isn't indexed.
+    pub fn touch(&mut self, path: &str, new_mtime: i64) -> Result<bool> {
+        let Some(&pos) = self.by_path.get(path) else {
+            return Ok(false);
+        };
+        self.remove_mtime(self.entries[pos].mtime, pos);
+        self.add_mtime(new_mtime, pos);
+        let entry = &mut self.entries[pos];
+        entry.mtime = new_mtime;
+        let tree = self.db.open_tree("entries")?;
+        tree.insert(path.as_bytes(), self.format.encode(&*entry)?)?;
+        tree.flush()?;
+        self.invalidate_hash(path, Some(new_mtime));
+        Ok(true)
+    }
+
+    /// BLAKE3 hash of the indexed file at `path`, computed on first use and cached in
+    /// the `hashes` tree. `None` for directories, unindexed paths and unreadable files.
+    pub fn content_hash(&self, path: &str) -> Result<Option<[u8; 32]>> {
+        match self.lookup_by_path(path) {
+            Some(entry) => self.entry_hash(entry),
+            None => Ok(None),
+        }
+    }
+
+    fn entry_hash(&self, entry: &FileEntry) -> Result<Option<[u8; 32]>> {
+        if entry.is_dir {
+            return Ok(None);
+        }
+        let tree = self.db.open_tree("hashes")?;
+        if let Some(hash) = self.cached_hash(&tree, entry)? {
+            return Ok(Some(hash));
+        }
+        let mut hasher = blake3::Hasher::new();
+        let hashed = fs::File::open(&entry.path).and_then(|f| hasher.update_reader(f).map(drop));
+        if let Err(err) = hashed {
+            tracing::warn!(path = %entry.path, error = %err, "failed to hash file");
+            return Ok(None);
+        }
+        let hash = *hasher.finalize().as_bytes();
+        tree.insert(
+            entry.path.as_bytes(),
+            self.format.encode(&(entry.mtime, entry.size, hash))?,
+        )?;
+        Ok(Some(hash))
+    }
+
+    /// The hash cached for `entry` in `tree`, if it was taken at the entry's current
+    /// mtime and size; those guard against stale records.
+    fn cached_hash(&self, tree: &sled::Tree, entry: &FileEntry) -> Result<Option<[u8; 32]>> {
+        let Some(v) = tree.get(entry.path.as_bytes())? else {
+            return Ok(None);
+        };
+        Ok(match self.format.decode::<(i64, u64, [u8; 32])>(&v) {
+            Ok((mtime, size, hash)) if mtime == entry.mtime && size == entry.size => Some(hash),
+            _ => None,
+        })
+    }
+
+    /// Hash `paths` (every indexed file if empty) with BLAKE3 into the `hashes`
+    /// tree used by `content_hash`, so `find_duplicates_by_checksum` can group them.
+    /// Reads every file not hashed since it last changed, so callers should only do
+    /// this when asked to.
+    pub fn index_checksums(&self, paths: &[String]) -> Result<()> {
+        if paths.is_empty() {
+            for e in &self.entries {
+                self.entry_hash(e)?;
+            }
+        } else {
+            for path in paths {
+                let e = self
+                    .lookup_by_path(path)
+                    .ok_or_else(|| LesError::NotIndexed(path.clone()))?;
+                self.entry_hash(e)?;
+            }
+        }
+        self.db.open_tree("hashes")?.flush()?;
+        Ok(())
+    }
+
+    /// Groups of two or more indexed files whose checksums, as stored by
+    /// `index_checksums` or `content_hash`, are identical. Unlike `find_duplicates`
+    /// this never reads a file: files without a current checksum are left out.
+    pub fn find_duplicates_by_checksum(&self) -> Result<Vec<Vec<&FileEntry>>> {
+        let tree = self.db.open_tree("hashes")?;
+        let mut by_hash: HashMap<[u8; 32], Vec<&FileEntry>> = HashMap::new();
+        for e in self.entries.iter().filter(|e| !e.is_dir) {
+            if let Some(hash) = self.cached_hash(&tree, e)? {
+                by_hash.entry(hash).or_default().push(e);
+            }
+        }
+        let mut groups: Vec<_> = by_hash.into_values().filter(|g| g.len() > 1).collect();
+        for g in &mut groups {
+            g.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        groups.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+        Ok(groups)
+    }
+
+    /// Groups of two or more indexed files with identical contents. Only files that
+    /// share a size are hashed, and hashes are reused across calls via `content_hash`.
+    pub fn find_duplicates(&self) -> Result<Vec<Vec<&FileEntry>>> {
+        let mut by_size: HashMap<u64, Vec<&FileEntry>> = HashMap::new();
+        for e in self.entries.iter().filter(|e| !e.is_dir && e.size > 0) {
+            by_size.entry(e.size).or_default().push(e);
+        }
+        let mut groups = Vec::new();
+        for same_size in by_size.into_values().filter(|g| g.len() > 1) {
+            let mut by_hash: HashMap<[u8; 32], Vec<&FileEntry>> = HashMap::new();
+            for e in same_size {
+                if let Some(hash) = self.entry_hash(e)? {
+                    by_hash.entry(hash).or_default().push(e);
+                }
+            }
+            groups.extend(by_hash.into_values().filter(|g| g.len() > 1));
+        }
+        self.db.open_tree("hashes")?.flush()?;
+        for g in &mut groups {
+            g.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        groups.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+        Ok(groups)
+    }
+
+    /// Drop the cached hash for `path` unless it was taken at `mtime`.
+    fn invalidate_hash(&self, path: &str, mtime: Option<i64>) {
+        let Ok(tree) = self.db.open_tree("hashes") else {
+            return;
+        };
+        let cached_mtime = tree
+            .get(path.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|v| self.format.decode::<(i64, u64, [u8; 32])>(&v).ok())
+            .map(|(m, _, _)| m);
+        if cached_mtime.is_some() && cached_mtime != mtime {
+            let _ = tree.remove(path.as_bytes());
+        }
+    }
+
+    /// Root index for a path outside a rebuild: the longest root containing it.
+    fn root_idx_for(&self, path: &Path) -> u16 {
+        self.roots
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| path.starts_with(r))
+            .max_by_key(|(_, r)| r.len())
+            .map(|(i, _)| i as u16)
+            .unwrap_or(0)
+    }
+
+    fn stored_tags(&self, path: &str) -> Option<Vec<String>> {
+        let v = self.db.open_tree("tags").ok()?.get(path.as_bytes()).ok()??;
+        self.format.decode(&v).ok()
+    }
+
+    /// Apply FS change: simple strategy – reindex that path or remove it.
+    /// `excludes` comes from `build_exclude_set`; `walk` should match the last rebuild.
+    pub fn update_path(&mut self, path: &Path, excludes: Option<&ExcludeSet>, walk: WalkOptions) {
+        let s = path.to_string_lossy().to_string();
+
+        // Remove any existing record for this path
+        self.remove_entries(&s);
+
+        let root = self
+            .roots
+            .get(usize::from(self.root_idx_for(path)))
+            .map_or(Path::new("/"), Path::new);
+        let depth = path.strip_prefix(root).map_or(0, |rel| rel.components().count());
+        let outside_walk = depth < walk.walk_min_depth
+            || walk.walk_max_depth.is_some_and(|max| depth > max);
+        if outside_walk || should_skip(path, root, excludes, walk.exclude_min_depth) {
+            self.invalidate_hash(&s, None);
+            if let Ok(tree) = self.db.open_tree("entries") {
+                let _ = tree.remove(s.as_bytes());
+                let _ = tree.flush();
+            }
+            return;
+        }
+
+        if let Ok(mut entry) = FileEntry::try_from(path) {
+            entry.tags = self.stored_tags(&s).unwrap_or_default();
+            entry.root_idx = self.root_idx_for(path);
+            if walk.fingerprint && !entry.is_dir {
+                entry.content_fingerprint = read_fingerprint(path);
+            }
+            self.invalidate_hash(&s, Some(entry.mtime));
+
+            // Serialize BEFORE pushing entry (fixes borrow-of-moved-value)
+            if let Ok(tree) = self.db.open_tree("entries") {
+                if let Ok(val) = self.format.encode(&entry) {
+                    let _ = tree.insert(s.as_bytes(), val);
+                    let _ = tree.flush();
+                }
+            }
+
+            // Now we can move entry
+            self.insert_entry(entry);
+
+        } else {
+            // path no longer exists -> remove from DB
+            self.invalidate_hash(&s, None);
+            if let Ok(tree) = self.db.open_tree("entries") {
+                let _ = tree.remove(s.as_bytes());
+                let _ = tree.flush();
+            }
+        }
+    }
+
+    /// Lazily yield the entries matching `q`, in index order.
+    pub fn query<'a>(&'a self, q: &'a Query) -> Result<impl Iterator<Item = &'a FileEntry> + 'a> {
+        q.validate()
+            .map_err(|errors| anyhow::anyhow!(errors.join("; ")))?;
+        let matcher = build_matcher(q)?;
+        let mtime_range = q.mtime_range();
+        Ok(self
+            .candidates(q)?
+            .filter(move |e| entry_matches(e, q, &matcher, mtime_range)))
+    }
+
+    /// Entries that may match `q`, narrowed by the trigram index and `modified_by_pid`
+    /// where possible; `entry_matches` still has to be applied to each.
+    fn candidates<'a>(
+        &'a self,
+        q: &'a Query,
+    ) -> Result<Box<dyn Iterator<Item = &'a FileEntry> + 'a>> {
+        // Any one indexed pattern narrows the search when all patterns must match;
+        // with `Any`, only a lone pattern does.
+        let indexed = |mode: &PatternMode| match mode {
+            PatternMode::Substr => self.trigrams.is_some(),
+            PatternMode::Prefix => self.prefixes.is_some(),
+            _ => false,
+        };
+        let patterns = q.effective_patterns();
+        let narrowing = match q.multi_logic {
+            MultiLogic::All => patterns.iter().find(|(_, m)| indexed(m)),
+            MultiLogic::Any if patterns.len() == 1 => patterns.first(),
+            MultiLogic::Any => None,
+        };
+        let positions = match narrowing {
+            Some((pattern, PatternMode::Substr)) => {
+                self.trigrams.as_ref().and_then(|t| t.candidates(pattern))
+            }
+            Some((pattern, PatternMode::Prefix)) => {
+                self.prefixes.as_ref().and_then(|p| p.candidates(pattern))
+            }
+            _ => None,
+        };
+        let candidates: Box<dyn Iterator<Item = &'a FileEntry> + 'a> = match positions {
+            Some(positions) => Box::new(positions.into_iter().map(|i| &self.entries[i])),
+            None => Box::new(self.entries.iter()),
+        };
+        let candidates = match q.modified_by_pid {
+            Some(pid) => {
+                let open = open_paths(pid)?;
+                Box::new(candidates.filter(move |e| open.contains(e.path.as_str())))
+            }
+            None => candidates,
+        };
+        Ok(candidates)
+    }
+
+    /// Run `q` and collect the matches; see `query` for the lazy form.
+    pub fn run_query(&self, q: &Query) -> Result<QueryResult> {
+        q.validate()
+            .map_err(|errors| anyhow::anyhow!(errors.join("; ")))?;
+        let mut scanned = 0;
+        let mut entries: Vec<FileEntry> = if q.matches_everything() {
+            // "Show everything": skip the matcher and per-entry filtering.
+            scanned = self.entries.len();
+            self.entries.clone()
+        } else {
+            let matcher = build_matcher(q)?;
+            let mtime_range = q.mtime_range();
+            self.candidates(q)?
+                .inspect(|_| scanned += 1)
+                .filter(|e| entry_matches(e, q, &matcher, mtime_range))
+                .cloned()
+                .collect()
+        };
+        if let Some(ref needle) = q.content {
+            for e in &mut entries {
+                e.content_hits =
+                    content_hits(&e.path, needle, q.content_line_max_len, q.content_encoding);
+            }
+        }
+        if let Some(ref near) = q.near_path {
+            // Stable, so ties stay in index (path) order.
+            entries.sort_by_cached_key(|e| path_distance(Path::new(near), Path::new(&e.path)));
+        }
+        Ok(QueryResult { entries, scanned })
+    }
+}
+
+/// Number of component positions at which `a` and `b` differ: past their common
+/// prefix, the length of the longer one.
+fn path_distance(a: &Path, b: &Path) -> usize {
+    let common = a
+        .components()
+        .zip(b.components())
+        .take_while(|(x, y)| x == y)
+        .count();
+    a.components().count().max(b.components().count()) - common
+}
+
+/// Apply every filter in `q` to `e`; `matcher` is the compiled `q.pattern`.
+/// `mtime_range` is `q.mtime_range()`, resolved once per query.
+///
+/// How much a filter rejects depends on the query, so they run cheapest first: flag
+/// and numeric comparisons, the fingerprint and tag lookups, exclude substrings
+/// (~35 ns each per entry), root and ancestor checks that split paths into
+/// components (~110 ns each), then the pattern and finally file contents. See
+/// `BENCHMARKS.md`.
+fn entry_matches(
+    e: &FileEntry,
+    q: &Query,
+    matcher: &Matcher,
+    mtime_range: (Option<i64>, Option<i64>),
+) -> bool {
+    if q.files_only && e.is_dir {
+        return false;
+    }
+    if q.dirs_only && !e.is_dir {
+        return false;
+    }
+    if q.sparse_only && !e.is_sparse {
+        return false;
+    }
+    if q.executable_only && (e.is_dir || !e.is_executable()) {
+        return false;
+    }
+
+    if let Some(min) = q.min_size {
+        if e.size < min {
+            return false;
+        }
+    }
+    if let Some(max) = q.max_size {
+        if e.size > max {
+            return false;
+        }
+    }
+    if let Some(min) = mtime_range.0 {
+        if e.mtime < min {
+            return false;
+        }
+    }
+    if let Some(max) = mtime_range.1 {
+        if e.mtime > max {
+            return false;
+        }
+    }
+    if let Some(prefix) = &q.fingerprint_prefix {
+        // Zero padding would otherwise let a short file match a prefix of NULs.
+        let matched = e.size >= prefix.len() as u64
+            && e
+                .content_fingerprint
+                .is_some_and(|fp| fp.starts_with(prefix));
+        if !matched {
+            return false;
+        }
+    }
+
+    if !q.has_tags.iter().all(|t| e.tags.contains(t)) {
+        return false;
+    }
+
+    // exclude filter
+    for ex in &q.exclude {
+        if e.path.contains(ex) {
+            return false;
+        }
+    }
+
+    // root filter, by path component so `/home` doesn't match `/homestead`
+    if !q.roots.is_empty() && !q.roots.iter().any(|r| Path::new(&e.path).starts_with(r)) {
+        return false;
+    }
+
+    if let Some(ref target) = q.path_prefix_of {
+        if e.path == *target || !Path::new(target).starts_with(&e.path) {
+            return false;
+        }
+    }
+
+    if !matcher(&e.path) {
+        return false;
+    }
+
+    // content search (slow, optional)
+    if let Some(ref content_pattern) = q.content {
+        if e.is_dir {
+            return false;
+        }
+        if !q.follow_symlinks_in_content
+            && fs::symlink_metadata(&e.path).map_or(true, |md| md.file_type().is_symlink())
+        {
+            return false;
+        }
+        let found = if q.content_mmap {
+            file_contains_mmap(&e.path, content_pattern, q.content_encoding)
+        } else {
+            file_contains_encoded(&e.path, content_pattern, q.content_encoding)
+        };
+        if !found {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Largest frame `read_frame` will accept.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Write one protocol frame: a 4-byte big-endian length followed by the body.
+pub fn write_frame<W: Write>(w: &mut W, body: &[u8]) -> Result<()> {
+    let len = u32::try_from(body.len())?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(body)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Read one protocol frame. Returns `None` on a clean EOF before the length prefix.
+pub fn read_frame<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        anyhow::bail!("frame too large: {len} bytes");
+    }
+    let mut body = vec![0u8; len as usize];
+    r.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Compile every pattern of `q` and combine them per `q.multi_logic`.
+fn build_matcher(q: &Query) -> Result<Matcher> {
+    let mut matchers = q
+        .effective_patterns()
+        .into_iter()
+        .map(|(pattern, mode)| build_single_matcher(pattern, mode, q))
+        .collect::<Result<Vec<_>>>()?;
+    if matchers.len() == 1 {
+        return Ok(matchers.remove(0));
+    }
+    Ok(match q.multi_logic {
+        MultiLogic::All => Box::new(move |path: &str| matchers.iter().all(|m| m(path))),
+        MultiLogic::Any => Box::new(move |path: &str| matchers.iter().any(|m| m(path))),
+    })
+}
+
+fn build_single_matcher(pattern: &str, mode: &PatternMode, q: &Query) -> Result<Matcher> {
+    let filename_only = q.match_filename_only;
+    match mode {
+        // Globs only ever see the file name; entries without one (`/`) never match.
+        PatternMode::Glob => {
+            let matcher = build_path_matcher(pattern, PatternMode::Glob, false)?;
+            Ok(Box::new(move |path: &str| {
+                Path::new(path)
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .is_some_and(&matcher)
+            }))
+        }
+        // Only the immediate parent's name, e.g. `tests` for `/src/tests/a.rs`.
+        PatternMode::GlobDir => {
+            let matcher = build_path_matcher(pattern, PatternMode::GlobDir, false)?;
+            Ok(Box::new(move |path: &str| {
+                Path::new(path)
+                    .parent()
+                    .and_then(|p| p.file_name())
+                    .and_then(|s| s.to_str())
+                    .is_some_and(&matcher)
+            }))
+        }
+        PatternMode::Prefix => {
+            let pattern = pattern.to_lowercase();
+            let (dir, name) = match pattern.rsplit_once('/') {
+                Some((dir, name)) => (Some(dir.to_string()), name.to_string()),
+                None => (None, pattern),
+            };
+            // `src` must be a whole trailing component: `/a/src`, not `/a/mysrc`.
+            let dir_suffix = dir.as_ref().map(|d| format!("/{d}"));
+            Ok(Box::new(move |path: &str| {
+                let path = Path::new(path);
+                let name_matches = path
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .is_some_and(|n| n.to_lowercase().starts_with(&name));
+                if !name_matches {
+                    return false;
+                }
+                let (Some(dir), Some(dir_suffix)) = (&dir, &dir_suffix) else {
+                    return true;
+                };
+                let Some(parent) = path.parent().and_then(|p| p.to_str()) else {
+                    return false;
+                };
+                let parent = parent.to_lowercase();
+                match dir.as_str() {
+                    "" => parent == "/",
+                    d if d.starts_with('/') => parent == d,
+                    d => parent == d || parent.ends_with(dir_suffix.as_str()),
+                }
+            }))
+        }
+        PatternMode::Regex => {
+            let re = build_regex(pattern, q.regex_flags.as_deref())?;
+            Ok(Box::new(move |path: &str| {
+                re.is_match(match_target(path, filename_only))
+            }))
+        }
+        PatternMode::Substr => {
+            // Substring search has always been case-insensitive; regexes opt in with `(?i)`.
+            let matcher = build_path_matcher(pattern, PatternMode::Substr, true)?;
+            Ok(Box::new(move |path: &str| {
+                matcher(match_target(path, filename_only))
+            }))
+        }
+    }
+}
+
+/// Compile `pattern` into a predicate over strings, as `Index::run_query` does.
+///
+/// The predicate is applied to whatever it is given: `run_query` passes file names
+/// for globs and full paths (or file names) otherwise. Glob patterns may be
+/// `;`-separated alternatives.
+pub fn build_path_matcher(
+    pattern: &str,
+    mode: PatternMode,
+    case_insensitive: bool,
+) -> Result<Matcher> {
+    match mode {
+        PatternMode::Glob | PatternMode::GlobDir => {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in glob_patterns(pattern) {
+                builder.add(
+                    GlobBuilder::new(pattern)
+                        .case_insensitive(case_insensitive)
+                        .build()?,
+                );
+            }
+            let set: GlobSet = builder.build()?;
+            Ok(Box::new(move |s: &str| set.is_match(s)))
+        }
+        PatternMode::Regex => {
+            let re = RegexBuilder::new(pattern)
+                .case_insensitive(case_insensitive)
+                .build()?;
+            Ok(Box::new(move |s: &str| re.is_match(s)))
+        }
+        PatternMode::Substr if case_insensitive => {
+            let needle = pattern.to_lowercase();
+            Ok(Box::new(move |s: &str| s.to_lowercase().contains(&needle)))
+        }
+        PatternMode::Substr => {
+            let needle = pattern.to_string();
+            Ok(Box::new(move |s: &str| s.contains(&needle)))
+        }
+        PatternMode::Prefix if case_insensitive => {
+            let prefix = pattern.to_lowercase();
+            Ok(Box::new(move |s: &str| s.to_lowercase().starts_with(&prefix)))
+        }
+        PatternMode::Prefix => {
+            let prefix = pattern.to_string();
+            Ok(Box::new(move |s: &str| s.starts_with(&prefix)))
+        }
+    }
+}
+
+/// Total size in bytes of the files under the database directory `db_path`, as
+/// `Index::size_on_disk` measures it, for callers that shouldn't borrow the index
+/// for the whole walk.
+pub fn db_size_on_disk(db_path: &Path) -> Result<u64> {
+    let mut total = 0;
+    for e in WalkDir::new(db_path).skip_hidden(false) {
+        let e = e?;
+        if e.file_type().is_file() {
+            total += e.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Whether the glob `pattern` matches the full path `path`, not just its file name.
+///
+/// `*`, `?` and `[abc]` stay within one component and `**` spans any number of them.
+/// A pattern starting with `/` is anchored at the root; any other pattern floats and
+/// may match the trailing components, so `src/*.rs` matches `/home/you/src/main.rs`.
+pub fn glob_match_full_path(pattern: &str, path: &str) -> Result<bool> {
+    full_path_glob_match(pattern, path, false)
+}
+
+/// Like `glob_match_full_path`, ignoring case, so `*.JPG` matches `/photos/a.jpg`.
+pub fn glob_match_full_path_case_insensitive(pattern: &str, path: &str) -> Result<bool> {
+    full_path_glob_match(pattern, path, true)
+}
+
+fn full_path_glob_match(pattern: &str, path: &str, case_insensitive: bool) -> Result<bool> {
+    let pattern = if pattern.starts_with('/') {
+        std::borrow::Cow::Borrowed(pattern)
+    } else {
+        std::borrow::Cow::Owned(format!("**/{pattern}"))
+    };
+    let glob = GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .case_insensitive(case_insensitive)
+        .build()?
+        .compile_matcher();
+    Ok(glob.is_match(path))
+}
+
+/// Compile `pattern` with `Query::regex_flags`, which may only hold `i`, `m`, `s` and `x`.
+fn build_regex(pattern: &str, flags: Option<&str>) -> Result<Regex> {
+    let mut builder = RegexBuilder::new(pattern);
+    for flag in flags.unwrap_or("").chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'x' => builder.ignore_whitespace(true),
+            other => anyhow::bail!("unknown regex flag {other:?} (use i, m, s or x)"),
+        };
+    }
+    Ok(builder.build()?)
+}
+
+/// The part of `path` a pattern is matched against: the whole path, or just its last component.
+fn match_target(path: &str, filename_only: bool) -> &str {
+    if !filename_only {
+        return path;
+    }
+    Path::new(path)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+}
+
+/// Glob queries may list several patterns separated by `;`, e.g. `*.rs;*.toml`.
+const GLOB_SEPARATOR: char = ';';
+
+fn glob_patterns(pattern: &str) -> impl Iterator<Item = &str> {
+    pattern.split(GLOB_SEPARATOR).filter(|p| !p.is_empty())
+}
+
+/// Chunk size for streaming content search.
+const CONTENT_CHUNK: usize = 64 * 1024;
+
+/// Whether the UTF-8 text file at `path` contains `needle`.
+///
+/// The file is streamed in chunks, so size is not limited by memory. Unreadable
+/// files and files that are not valid UTF-8 (e.g. binaries) never match.
+pub fn file_contains(path: &str, needle: &str) -> bool {
+    scan_file(path, needle.as_bytes()).is_some_and(|(found, utf8)| found && utf8)
+}
+
+/// Like `file_contains`, decoding the file as `encoding`.
+pub fn file_contains_encoded(path: &str, needle: &str, encoding: ContentEncoding) -> bool {
+    let latin1 = || {
+        latin1_bytes(needle).is_some_and(|n| scan_file(path, &n).is_some_and(|(found, _)| found))
+    };
+    match encoding {
+        ContentEncoding::Utf8 => file_contains(path, needle),
+        ContentEncoding::Latin1 => latin1(),
+        ContentEncoding::Auto => match scan_file(path, needle.as_bytes()) {
+            Some((found, true)) => found,
+            Some((_, false)) => latin1(),
+            None => false,
+        },
+    }
+}
+
+/// `needle` as ISO-8859-1 bytes, or `None` if it has characters Latin-1 can't encode.
+fn latin1_bytes(needle: &str) -> Option<Vec<u8>> {
+    needle
+        .chars()
+        .map(|c| u8::try_from(u32::from(c)).ok())
+        .collect()
+}
+
+fn decode_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
+/// Stream the file at `path` looking for `needle`. Returns whether it was found and
+/// whether the whole file is valid UTF-8, or `None` if the file can't be read.
+fn scan_file(path: &str, needle: &[u8]) -> Option<(bool, bool)> {
+    let mut file = fs::File::open(path).ok()?;
+    let finder = memchr::memmem::Finder::new(needle);
+    // `buf` holds the tail of the previous chunk (so matches can straddle chunks)
+    // followed by the newly read bytes.
+    let mut buf: Vec<u8> = Vec::with_capacity(CONTENT_CHUNK + needle.len());
+    let mut chunk = vec![0u8; CONTENT_CHUNK];
+    let mut utf8_pending: Vec<u8> = Vec::new();
+    let mut found = false;
+    let mut utf8 = true;
+
+    loop {
+        let n = match file.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        };
+
+        // Validate UTF-8 incrementally, carrying a split trailing character over,
+        // until the first invalid byte settles it.
+        if utf8 {
+            utf8_pending.extend_from_slice(&chunk[..n]);
+            match std::str::from_utf8(&utf8_pending) {
+                Ok(_) => utf8_pending.clear(),
+                Err(e) if e.error_len().is_none() => {
+                    utf8_pending.drain(..e.valid_up_to());
+                }
+                Err(_) => {
+                    utf8 = false;
+                    utf8_pending.clear();
+                }
+            }
+        }
+
+        if !found {
+            buf.extend_from_slice(&chunk[..n]);
+            if finder.find(&buf).is_some() {
+                found = true;
+            } else {
+                let keep = needle.len().saturating_sub(1).min(buf.len());
+                buf.drain(..buf.len() - keep);
+            }
+        }
+        // Reading on only matters while the rest could still make the file invalid UTF-8.
+        if found && !utf8 {
+            break;
+        }
+    }
+
+    Some((found, utf8 && utf8_pending.is_empty()))
+}
+
+/// Whether any line of the UTF-8 text file at `path` matches the regex `pattern`.
+///
+/// Lines are read one at a time, so patterns cannot span line breaks. Returns an
+/// error only if `pattern` does not compile; unreadable or non-UTF-8 files are `false`.
+pub fn file_matches_regex(path: &str, pattern: &str) -> Result<bool> {
+    let re = Regex::new(pattern)?;
+    let Ok(file) = fs::File::open(path) else {
+        return Ok(false);
+    };
+    for line in std::io::BufReader::new(file).lines() {
+        match line {
+            Ok(line) if re.is_match(&line) => return Ok(true),
+            Ok(_) => {}
+            Err(_) => return Ok(false),
+        }
+    }
+    Ok(false)
+}
+
+/// Paths of the files `pid` has open, resolved from the `/proc/<pid>/fd` symlinks.
+/// Sockets, pipes and the like are left out.
+#[cfg(target_os = "linux")]
+fn open_paths(pid: u32) -> Result<HashSet<String>> {
+    let dir = format!("/proc/{pid}/fd");
+    let fds = fs::read_dir(&dir).map_err(|e| anyhow::anyhow!("cannot read {dir}: {e}"))?;
+    let mut out = HashSet::new();
+    for fd in fds.flatten() {
+        // Closed between readdir and readlink, or not permitted: just skip it.
+        if let Ok(target) = fs::read_link(fd.path()) {
+            if target.is_absolute() {
+                out.insert(target.to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_paths(_pid: u32) -> Result<HashSet<String>> {
+    anyhow::bail!("modified_by_pid needs /proc and is only supported on Linux")
+}
+
+/// Every line of the file at `path` containing `needle`, with long lines cut to
+/// `max_len` bytes (on a character boundary).
+///
+/// Lines are streamed through fixed-size buffers, so a huge line without newlines
+/// (a minified or binary file) is searched without holding it in memory.
+fn content_hits(
+    path: &str,
+    needle: &str,
+    max_len: usize,
+    encoding: ContentEncoding,
+) -> Vec<ContentHit> {
+    let Ok(file) = fs::File::open(path) else {
+        return Vec::new();
+    };
+    let mut reader = std::io::BufReader::new(file);
+    let latin1_needle = match encoding {
+        ContentEncoding::Utf8 => None,
+        _ => latin1_bytes(needle),
+    };
+    let mut hits = Vec::new();
+    let mut line = LineSearch::new(needle, latin1_needle.as_deref(), max_len, encoding);
+    let mut offset = 0;
+    let mut line_num = 0;
+
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        if available.is_empty() {
+            // A last line without a newline.
+            if line.len > 0 {
+                line_num += 1;
+                hits.extend(line.finish(line_num, offset));
+            }
+            break;
+        }
+        let (piece, eol) = match memchr::memchr(b'\n', available) {
+            Some(i) => (&available[..i], true),
+            None => (available, false),
+        };
+        line.feed(piece);
+        let consumed = piece.len() + usize::from(eol);
+        reader.consume(consumed);
+        if eol {
+            line_num += 1;
+            let len = line.len + 1;
+            hits.extend(line.finish(line_num, offset));
+            offset += len;
+        }
+    }
+    hits
+}
+
+/// One line of `content_hits` being read: the start of it that a hit shows, and
+/// just enough of the rest to find `needle` across reads.
+struct LineSearch<'a> {
+    max_len: usize,
+    encoding: ContentEncoding,
+    /// The first bytes of the line: `max_len` plus room to finish a split character.
+    head: Vec<u8>,
+    /// Unsearched tail of the previous piece followed by the current one.
+    window: Vec<u8>,
+    utf8_needle: Option<memchr::memmem::Finder<'a>>,
+    latin1_needle: Option<memchr::memmem::Finder<'a>>,
+    found_utf8: bool,
+    found_latin1: bool,
+    /// Trailing bytes of a character split between pieces, while the line is UTF-8.
+    utf8_pending: Vec<u8>,
+    utf8: bool,
+    len: usize,
+}
+
+impl<'a> LineSearch<'a> {
+    fn new(
+        needle: &'a str,
+        latin1_needle: Option<&'a [u8]>,
+        max_len: usize,
+        encoding: ContentEncoding,
+    ) -> Self {
+        Self {
+            max_len,
+            encoding,
+            head: Vec::new(),
+            window: Vec::new(),
+            utf8_needle: (encoding != ContentEncoding::Latin1)
+                .then(|| memchr::memmem::Finder::new(needle)),
+            latin1_needle: latin1_needle.map(memchr::memmem::Finder::new),
+            found_utf8: false,
+            found_latin1: false,
+            utf8_pending: Vec::new(),
+            utf8: true,
+            len: 0,
+        }
+    }
+
+    fn feed(&mut self, piece: &[u8]) {
+        self.len += piece.len();
+        let room = self.max_len.saturating_add(3).saturating_sub(self.head.len());
+        self.head.extend_from_slice(&piece[..room.min(piece.len())]);
+
+        if self.utf8 {
+            self.utf8_pending.extend_from_slice(piece);
+            match std::str::from_utf8(&self.utf8_pending) {
+                Ok(_) => self.utf8_pending.clear(),
+                Err(e) if e.error_len().is_none() => {
+                    self.utf8_pending.drain(..e.valid_up_to());
+                }
+                Err(_) => {
+                    self.utf8 = false;
+                    self.utf8_pending.clear();
+                }
+            }
+        }
+
+        self.window.extend_from_slice(piece);
+        let mut keep = 0;
+        for (finder, found) in [
+            (&self.utf8_needle, &mut self.found_utf8),
+            (&self.latin1_needle, &mut self.found_latin1),
+        ] {
+            if let Some(finder) = finder {
+                if !*found && finder.find(&self.window).is_some() {
+                    *found = true;
+                }
+                keep = keep.max(finder.needle().len().saturating_sub(1));
+            }
+        }
+        let keep = keep.min(self.window.len());
+        self.window.drain(..self.window.len() - keep);
+    }
+
+    /// The hit for the line read so far, if it matched, and reset for the next line.
+    fn finish(&mut self, line_num: usize, byte_offset: usize) -> Option<ContentHit> {
+        let utf8 = self.utf8 && self.utf8_pending.is_empty();
+        let found = match self.encoding {
+            ContentEncoding::Utf8 => self.found_utf8,
+            ContentEncoding::Latin1 => self.found_latin1,
+            ContentEncoding::Auto if utf8 => self.found_utf8,
+            ContentEncoding::Auto => self.found_latin1,
+        };
+        let hit = found.then(|| {
+            // Auto decodes by whether the whole line is UTF-8, not just its head.
+            let encoding = match self.encoding {
+                ContentEncoding::Auto if utf8 => ContentEncoding::Utf8,
+                ContentEncoding::Auto => ContentEncoding::Latin1,
+                other => other,
+            };
+            let line = decode_line(&self.head, encoding);
+            let line = line.trim_end_matches('\r');
+            let mut end = line.len().min(self.max_len);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            ContentHit {
+                line_num,
+                line: line[..end].to_string(),
+                byte_offset,
+            }
+        });
+        self.head.clear();
+        self.window.clear();
+        self.utf8_pending.clear();
+        self.found_utf8 = false;
+        self.found_latin1 = false;
+        self.utf8 = true;
+        self.len = 0;
+        hit
+    }
+}
+
+fn decode_line(bytes: &[u8], encoding: ContentEncoding) -> std::borrow::Cow<'_, str> {
+    match encoding {
+        ContentEncoding::Utf8 => String::from_utf8_lossy(bytes),
+        ContentEncoding::Latin1 => decode_latin1(bytes).into(),
+        ContentEncoding::Auto => match std::str::from_utf8(bytes) {
+            Ok(s) => s.into(),
+            Err(_) => decode_latin1(bytes).into(),
+        },
+    }
+}
+
+/// Walk `root` (absolute) with jwalk, pruning excluded paths and, when following
+/// symlinks, directories already visited. Failures come back as `(path, error)`.
+fn walk_root(
+    root: &Path,
+    excludes: Option<&ExcludeSet>,
+    walk: WalkOptions,
+    dirs_skipped: Arc<AtomicUsize>,
+) -> impl Iterator<Item = std::result::Result<FileEntry, (String, std::io::Error)>> {
+    // jwalk reads directories on a rayon pool; pruning happens per directory
+    // listing, so the predicate and counter must be shareable across threads.
+    let excludes_for_walk = excludes.cloned().map(Arc::new);
+    let root_for_walk = root.to_path_buf();
+    // jwalk's own loop check compares raw link targets with ancestor paths, which
+    // relative links like `ln -s .. up` get past; track directory identities too.
+    // The root itself comes through `process_read_dir` first, so it is recorded too.
+    let visited = walk
+        .follow_symlinks
+        .then(|| Arc::new(Mutex::new(HashSet::<(u64, u64)>::new())));
+    WalkDir::new(root)
+        .follow_links(walk.follow_symlinks)
+        .skip_hidden(false)
+        .min_depth(walk.walk_min_depth)
+        .max_depth(walk.walk_max_depth.unwrap_or(usize::MAX))
+        .process_read_dir(move |_depth, _path, _state, children| {
+            children.retain(|child| match child {
+                Ok(de) => {
+                    let skip = should_skip(
+                        &de.path(),
+                        &root_for_walk,
+                        excludes_for_walk.as_deref(),
+                        walk.exclude_min_depth,
+                    );
+                    if skip && de.file_type().is_dir() {
+                        dirs_skipped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    !skip
+                }
+                Err(_) => true,
+            });
+            let Some(visited) = &visited else {
+                return;
+            };
+            for de in children.iter_mut().flatten() {
+                if !de.file_type().is_dir() {
+                    continue;
+                }
+                let Ok(md) = fs::metadata(de.path()) else {
+                    continue;
+                };
+                if !visited.lock().unwrap().insert((md.dev(), md.ino())) {
+                    tracing::warn!("symlink cycle detected at {}", de.path().display());
+                    de.read_children_path = None;
+                }
+            }
+        })
+        .into_iter()
+        .flat_map(move |e| {
+            let mut e = match e {
+                Ok(e) => e,
+                Err(err) => return std::iter::once(Err(walk_error(err))).chain(None),
+            };
+            let path = e.path().to_string_lossy().to_string();
+            let entry = match fs::metadata(e.path()) {
+                Ok(md) => {
+                    let mut entry = entry_from_metadata(path, &md);
+                    if walk.fingerprint && !entry.is_dir {
+                        entry.content_fingerprint = read_fingerprint(&e.path());
+                    }
+                    Ok(entry)
+                }
+                Err(err) => Err((path, err)),
+            };
+            // jwalk yields a directory it couldn't list as a normal entry and keeps
+            // the failure on the side; surface it like any other error.
+            let unlisted = e.read_children_error.take().map(|err| Err(walk_error(err)));
+            std::iter::once(entry).chain(unlisted)
+        })
+}
+
+fn walk_error(err: jwalk::Error) -> (String, std::io::Error) {
+    let path = err
+        .path()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let message = err.to_string();
+    let err = err
+        .into_io_error()
+        .unwrap_or_else(|| std::io::Error::other(message));
+    (path, err)
+}
+
+/// Lazily scan `root` without building an `Index`, yielding entries as the walk
+/// reaches them. Hidden files are included and symlinks aren't followed; `excludes`
+/// work as for `Index::rebuild`. Unreadable entries come back as errors.
+pub fn scan_dir(
+    root: &Path,
+    excludes: &[String],
+) -> impl Iterator<Item = Result<FileEntry>> + Send {
+    let setup = std::path::absolute(root)
+        .map_err(anyhow::Error::from)
+        .and_then(|root| Ok((root, build_exclude_set(excludes)?)));
+    let walk = WalkOptions::default();
+    let iter: Box<dyn Iterator<Item = Result<FileEntry>> + Send> = match setup {
+        Ok((root, excludes)) if !should_skip(&root, &root, excludes.as_ref(), 0) => Box::new(
+            walk_root(&root, excludes.as_ref(), walk, Arc::default())
+                .map(|e| e.map_err(|(path, err)| anyhow::anyhow!("{path}: {err}"))),
+        ),
+        Ok(_) => Box::new(std::iter::empty()),
+        Err(e) => Box::new(std::iter::once(Err(e))),
+    };
+    iter
+}
+
+fn entry_from_metadata(path: String, md: &fs::Metadata) -> FileEntry {
+    let is_dir = md.is_dir();
+    let size = if is_dir { 0 } else { md.size() };
+    let mtime = match md.modified() {
+        Ok(t) => {
+            let dt: DateTime<Local> = t.into();
+            dt.timestamp()
+        }
+        Err(_) => 0,
+    };
+
+    FileEntry {
+        path,
+        is_dir,
+        size,
+        mtime,
+        tags: Vec::new(),
+        root_idx: 0,
+        dev: md.dev(),
+        ino: md.ino(),
+        is_sparse: !is_dir && size > md.blocks() * 512 * 2,
+        mode: md.mode() & 0o7777,
+        content_hits: Vec::new(),
+        content_fingerprint: None,
+    }
+}
+
+/// Read `FileEntry::content_fingerprint` for `path`. Failures leave the entry
+/// without one rather than dropping it from the index.
+fn read_fingerprint(path: &Path) -> Option<[u8; FINGERPRINT_LEN]> {
+    use std::io::{Seek, SeekFrom};
+
+    let read = || -> std::io::Result<[u8; FINGERPRINT_LEN]> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut fp = [0u8; FINGERPRINT_LEN];
+        let (head, tail) = fp.split_at_mut(FINGERPRINT_HALF);
+        let n = read_up_to(&mut file, head)?;
+        if len > FINGERPRINT_HALF as u64 {
+            file.seek(SeekFrom::Start(len - FINGERPRINT_HALF as u64))?;
+            read_up_to(&mut file, tail)?;
+        } else {
+            tail[..n].copy_from_slice(&head[..n]);
+        }
+        Ok(fp)
+    };
+    match read() {
+        Ok(fp) => Some(fp),
+        Err(err) => {
+            tracing::debug!(path = %path.display(), error = %err, "failed to fingerprint file");
+            None
+        }
+    }
+}
+
+/// Fill as much of `buf` as the reader has, returning how many bytes were read.
+fn read_up_to(r: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Files smaller than this are read directly; mapping them costs more than it saves.
+const MMAP_MIN_SIZE: u64 = 4096;
+
+fn file_contains_mmap(path: &str, needle: &str, encoding: ContentEncoding) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    match file.metadata() {
+        Ok(md) if md.len() >= MMAP_MIN_SIZE => {}
+        _ => return file_contains_encoded(path, needle, encoding),
+    }
+    // SAFETY: the map is read-only and dropped before returning. If another
+    // process truncates the file meanwhile we may fault, as with any mmap reader.
+    let map = match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(map) => map,
+        Err(_) => return file_contains_encoded(path, needle, encoding),
+    };
+    // The map isn't validated, so Auto simply accepts either byte form.
+    let utf8 = || memchr::memmem::find(&map, needle.as_bytes()).is_some();
+    let latin1 = || latin1_bytes(needle).is_some_and(|n| memchr::memmem::find(&map, &n).is_some());
+    match encoding {
+        ContentEncoding::Utf8 => utf8(),
+        ContentEncoding::Latin1 => latin1(),
+        ContentEncoding::Auto => utf8() || latin1(),
+    }
+}
+
+fn validate_entry_path(path: &str) -> std::result::Result<(), LesError> {
+    if !Path::new(path).is_absolute() {
+        return Err(LesError::InvalidPath(
+            "path must be non-empty and absolute".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Compiled exclude patterns, from `build_exclude_set`.
+///
+/// Plain strings go into one Aho-Corasick automaton, which is several times faster
+/// than the equivalent `*…*` globs; only patterns with glob syntax use the `GlobSet`.
+#[derive(Debug, Clone)]
+pub struct ExcludeSet {
+    literals: Option<AhoCorasick>,
+    globs: Option<GlobSet>,
+}
+
+impl ExcludeSet {
+    /// Whether any exclude matches somewhere in `path`.
+    pub fn is_match(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        self.literals
+            .as_ref()
+            .is_some_and(|ac| ac.is_match(path.as_os_str().as_bytes()))
+            || self.globs.as_ref().is_some_and(|set| set.is_match(path))
+    }
+}
+
+/// Compile exclude patterns for `should_skip`; `None` if there are none.
+///
+/// Each pattern matches anywhere in the path, as if wrapped in `*…*`, so a plain
+/// string like `.git` keeps its substring meaning and `*.log` works as a glob.
+pub fn build_exclude_set(excludes: &[String]) -> Result<Option<ExcludeSet>> {
+    if excludes.is_empty() {
+        return Ok(None);
+    }
+    let (globs, literals): (Vec<&String>, Vec<&String>) = excludes
+        .iter()
+        .partition(|ex| ex.contains(['*', '?', '[', ']', '{', '}', '\\']));
+    let literals = if literals.is_empty() {
+        None
+    } else {
+        Some(AhoCorasick::new(literals)?)
+    };
+    let globs = if globs.is_empty() {
+        None
+    } else {
+        let mut builder = GlobSetBuilder::new();
+        for ex in globs {
+            builder.add(Glob::new(&format!("*{ex}*"))?);
+        }
+        Some(builder.build()?)
+    };
+    Ok(Some(ExcludeSet { literals, globs }))
+}
+
+/// Whether the walk should leave out `path` (and everything below it).
+///
+/// Built-in virtual and temporary filesystems are skipped unless `root` itself is
+/// in one, as for removable media mounted under `/run/media` or the temp directory.
+/// With `exclude_min_depth` 0,
+/// `excludes` are matched against the full path. Otherwise they only apply to paths
+/// at least that many components below `root`, and only see the components from
+/// that depth on: with 2, `/repo/.git/objects` is kept (`objects`) while
+/// `/repo/vendor/.git` is skipped (`.git`).
+fn should_skip(
+    path: &Path,
+    root: &Path,
+    excludes: Option<&ExcludeSet>,
+    exclude_min_depth: usize,
+) -> bool {
+    const DEFAULT_SKIP: &[&str] = &[
+        "/proc", "/sys", "/dev", "/run", "/tmp", "/var/run", "/var/tmp", "/var/cache",
+        "/var/lib/snapd",
+    ];
+    let s = path.to_string_lossy();
+    let default_skip = |s: &str| DEFAULT_SKIP.iter().any(|p| s.starts_with(p));
+
+    if default_skip(&s) && !default_skip(&root.to_string_lossy()) {
+        return true;
+    }
+
+    let Some(set) = excludes else {
+        return false;
+    };
+    if exclude_min_depth == 0 {
+        return set.is_match(s.as_ref());
+    }
+    let Ok(rel) = path.strip_prefix(root) else {
+        return set.is_match(s.as_ref());
+    };
+    if rel.components().count() < exclude_min_depth {
+        return false;
+    }
+    let tail: std::path::PathBuf = rel.components().skip(exclude_min_depth - 1).collect();
+    set.is_match(tail)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+
+    // Fails to compile if a new field makes `Index` lose `Send` or `Sync`.
+    static_assertions::assert_impl_all!(Index: Send, Sync);
+
+    /// Peak resident set size of this process in KiB, where `/proc` reports it.
+    fn peak_rss_kib() -> Option<u64> {
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    /// Write `contents` to a file in the temp directory unique to this process and test.
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("les_core-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn file_contains_missing_file() {
+        assert!(!file_contains("/nonexistent/les_core/file", "x"));
+    }
+
+    #[test]
+    fn file_contains_match_straddling_chunks() {
+        let mut data = vec![b'a'; CONTENT_CHUNK - 3];
+        data.extend_from_slice(b"needle");
+        data.extend(vec![b'a'; 100]);
+        let path = temp_file("straddle", &data);
+        assert!(file_contains(path.to_str().unwrap(), "needle"));
+        assert!(!file_contains(path.to_str().unwrap(), "needles"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_contains_needle_in_last_byte() {
+        let mut data = vec![b'a'; 3 * CONTENT_CHUNK];
+        data.push(b'z');
+        let path = temp_file("last-byte", &data);
+        assert!(file_contains(path.to_str().unwrap(), "z"));
+        assert!(file_contains(path.to_str().unwrap(), "az"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_contains_large_sparse_file() {
+        // 8 GiB of holes then the needle: only streaming can get through this.
+        let path = temp_file("sparse", b"");
+        let len = 8u64 << 30;
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 1).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b"z").unwrap();
+        drop(file);
+        let before = peak_rss_kib();
+        assert!(file_contains(path.to_str().unwrap(), "z"));
+        if let (Some(before), Some(after)) = (before, peak_rss_kib()) {
+            assert!(after - before < 64 * 1024, "peak RSS grew by {} KiB", after - before);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_contains_binary_never_matches() {
+        let mut data = b"ELF needle ".to_vec();
+        data.extend_from_slice(&[0xff, 0xfe, 0x00, 0x80]);
+        let path = temp_file("binary", &data);
+        assert!(!file_contains(path.to_str().unwrap(), "needle"));
+        // The needle comes after the invalid bytes too.
+        let mut data = vec![0xff; CONTENT_CHUNK + 10];
+        data.extend_from_slice(b"needle");
+        fs::write(&path, &data).unwrap();
+        assert_eq!(scan_file(path.to_str().unwrap(), b"needle"), Some((true, false)));
+        assert!(!file_contains(path.to_str().unwrap(), "needle"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_contains_utf8_split_at_chunk_boundary() {
+        // "é" is two bytes; put its first byte at the end of the first chunk.
+        let mut data = vec![b'a'; CONTENT_CHUNK - 1];
+        data.extend_from_slice("é needle".as_bytes());
+        let path = temp_file("split-utf8", &data);
+        assert!(file_contains(path.to_str().unwrap(), "é needle"));
+        assert_eq!(scan_file(path.to_str().unwrap(), b"needle"), Some((true, true)));
+        // A character cut off at the end of the file is not valid UTF-8.
+        data.truncate(CONTENT_CHUNK);
+        fs::write(&path, &data).unwrap();
+        assert_eq!(scan_file(path.to_str().unwrap(), b"a"), Some((true, false)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_contains_latin1() {
+        // "café" in ISO-8859-1.
+        let path = temp_file("latin1", b"caf\xe9 au lait");
+        let p = path.to_str().unwrap();
+        assert!(!file_contains(p, "café"));
+        assert!(file_contains_encoded(p, "café", ContentEncoding::Latin1));
+        assert!(file_contains_encoded(p, "café", ContentEncoding::Auto));
+        assert!(!file_contains_encoded(p, "thé", ContentEncoding::Auto));
+        // Needles Latin-1 can't encode never match.
+        assert!(!file_contains_encoded(p, "caf€", ContentEncoding::Latin1));
+        // Auto searches UTF-8 files as UTF-8.
+        fs::write(&path, "café au lait").unwrap();
+        assert!(file_contains_encoded(p, "café", ContentEncoding::Auto));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_matches_regex_lines() {
+        let path = temp_file("regex", b"first line\nversion = \"1.2\"\n");
+        let p = path.to_str().unwrap();
+        assert!(file_matches_regex(p, r#"^version = "\d+\.\d+"$"#).unwrap());
+        assert!(!file_matches_regex(p, "line\nversion").unwrap());
+        assert!(file_matches_regex(p, "(").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn query_roots_match_by_component() {
+        let mut index = Index::open_memory().unwrap();
+        for path in ["/home/a.txt", "/homestead/b.txt", "/homework/c.txt"] {
+            index.add_entry(FileEntry::new(path.into(), false, 1, 0)).unwrap();
+        }
+        let q = Query {
+            roots: vec!["/home".into()],
+            ..Query::default()
+        };
+        let paths: Vec<String> = index.run_query(&q).unwrap().into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, ["/home/a.txt"]);
+    }
+
+    #[test]
+    fn glob_match_full_path_floating() {
+        let m = |p, path| glob_match_full_path(p, path).unwrap();
+        assert!(m("*.rs", "/home/you/src/main.rs"));
+        assert!(m("*.rs", "main.rs"));
+        assert!(!m("*.rs", "/home/you/src/main.rs.bak"));
+        assert!(m("src/*.rs", "/home/you/src/main.rs"));
+        // `*` stays within one component.
+        assert!(!m("src/*.rs", "/home/you/src/bin/main.rs"));
+        assert!(m("src/**/*.rs", "/home/you/src/bin/main.rs"));
+        assert!(m("ma?n.rs", "/src/main.rs"));
+        assert!(!m("ma?n.rs", "/src/maiin.rs"));
+        assert!(m("[abc].txt", "/notes/b.txt"));
+        assert!(!m("[abc].txt", "/notes/d.txt"));
+    }
+
+    #[test]
+    fn glob_match_full_path_anchored() {
+        let m = |p, path| glob_match_full_path(p, path).unwrap();
+        assert!(m("/abs/*", "/abs/file"));
+        assert!(!m("/abs/*", "/abs/dir/file"));
+        assert!(!m("/abs/*", "/other/abs/file"));
+        assert!(m("/abs/**", "/abs/dir/file"));
+        assert!(m("/abs/**/*.rs", "/abs/a/b/c.rs"));
+        assert!(glob_match_full_path("[", "/x").is_err());
+    }
+
+    #[test]
+    fn glob_match_full_path_case() {
+        assert!(!glob_match_full_path("*.JPG", "/photos/a.jpg").unwrap());
+        assert!(glob_match_full_path_case_insensitive("*.JPG", "/photos/a.jpg").unwrap());
+        assert!(glob_match_full_path_case_insensitive("/Photos/*", "/photos/a.jpg").unwrap());
+        assert!(!glob_match_full_path_case_insensitive("/Photos/*", "/photos/2024/a.jpg").unwrap());
+    }
+
+    #[test]
+    fn pattern_mode_round_trip() {
+        for mode in [
+            PatternMode::Glob,
+            PatternMode::GlobDir,
+            PatternMode::Regex,
+            PatternMode::Substr,
+            PatternMode::Prefix,
+        ] {
+            assert_eq!(mode.to_string().parse::<PatternMode>().unwrap(), mode);
+        }
+        assert!("fuzzy".parse::<PatternMode>().is_err());
+        assert!("Glob".parse::<PatternMode>().is_err());
+        assert!("".parse::<PatternMode>().is_err());
+    }
+
+    #[test]
+    fn file_entry_age() {
+        let now = Local::now().timestamp();
+        let hour_ago = FileEntry::new("/a".into(), false, 0, now - 3600);
+        // Allow for the clock ticking over between `now` and the call.
+        assert!((3600..=3601).contains(&hour_ago.age_secs()));
+        assert!((3600..=3601).contains(&hour_ago.age().as_secs()));
+
+        let future = FileEntry::new("/b".into(), false, 0, now + 600);
+        assert!((-600..=-599).contains(&future.age_secs()));
+        assert_eq!(future.age(), Duration::ZERO);
+
+        let epoch = FileEntry::new("/c".into(), false, 0, i64::MIN);
+        assert_eq!(epoch.age_secs(), i64::MAX);
+    }
+
+    #[test]
+    fn content_hits_lines() {
+        let path = temp_file("hits", b"one\r\ntwo needle\nthree\nneedle four");
+        let hits = content_hits(path.to_str().unwrap(), "needle", 200, ContentEncoding::Utf8);
+        let got: Vec<_> = hits
+            .iter()
+            .map(|h| (h.line_num, h.line.as_str(), h.byte_offset))
+            .collect();
+        assert_eq!(got, [(2, "two needle", 5), (4, "needle four", 22)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn content_hits_long_line_is_capped() {
+        // 8 MiB without a newline, matching only at the very end.
+        let mut data = "é".repeat(4 << 20).into_bytes();
+        data.extend_from_slice(b"needle");
+        let path = temp_file("long-line", &data);
+        let hits = content_hits(path.to_str().unwrap(), "needle", 11, ContentEncoding::Utf8);
+        assert_eq!(hits.len(), 1);
+        // Cut on a character boundary at or below the cap.
+        assert_eq!(hits[0].line, "ééééé");
+        assert_eq!((hits[0].line_num, hits[0].byte_offset), (1, 0));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn content_hits_latin1_line() {
+        let path = temp_file("hits-latin1", b"plain\ncaf\xe9 au lait\n");
+        let hits = content_hits(path.to_str().unwrap(), "café", 200, ContentEncoding::Auto);
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].line_num, hits[0].line.as_str()), (2, "café au lait"));
+        let utf8 = content_hits(path.to_str().unwrap(), "café", 200, ContentEncoding::Utf8);
+        assert!(utf8.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn query_patterns_serde() {
+        let base = r#""files_only":false,"dirs_only":false,"roots":[],"exclude":[]"#;
+        let legacy: Query =
+            serde_json::from_str(&format!(r#"{{"pattern":"*.rs","mode":"glob",{base}}}"#)).unwrap();
+        assert_eq!(legacy.effective_patterns(), [("*.rs", &PatternMode::Glob)]);
+        assert_eq!(legacy.multi_logic, MultiLogic::All);
+
+        let json = format!(
+            r#"{{"patterns":[["*.rs","glob"],["test","substr"]],"multi_logic":"any",{base}}}"#
+        );
+        let q: Query = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            q.effective_patterns(),
+            [("*.rs", &PatternMode::Glob), ("test", &PatternMode::Substr)]
+        );
+        assert_eq!(q.multi_logic, MultiLogic::Any);
+
+        let back: Query = serde_json::from_str(&serde_json::to_string(&q).unwrap()).unwrap();
+        assert_eq!(back.patterns, q.patterns);
+        assert_eq!(back.multi_logic, MultiLogic::Any);
+        let legacy_back: Query =
+            serde_json::from_str(&serde_json::to_string(&legacy).unwrap()).unwrap();
+        assert_eq!(legacy_back.effective_patterns(), [("*.rs", &PatternMode::Glob)]);
+    }
+
+    #[test]
+    fn query_multi_logic() {
+        let mut index = Index::open_memory().unwrap();
+        for path in ["/src/main.rs", "/src/test_util.rs", "/docs/test.md"] {
+            index.add_entry(FileEntry::new(path.into(), false, 1, 0)).unwrap();
+        }
+        let run = |multi_logic| {
+            let q = Query {
+                patterns: vec![
+                    ("*.rs".into(), PatternMode::Glob),
+                    ("test".into(), PatternMode::Substr),
+                ],
+                multi_logic,
+                ..Query::default()
+            };
+            let result = index.run_query(&q).unwrap();
+            result.into_iter().map(|e| e.path).collect::<Vec<_>>()
+        };
+        assert_eq!(run(MultiLogic::All), ["/src/test_util.rs"]);
+        assert_eq!(
+            run(MultiLogic::Any),
+            ["/src/main.rs", "/src/test_util.rs", "/docs/test.md"]
+        );
+    }
+
+    #[test]
+    fn update_path_refreshes_mode_after_chmod() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("les_core-{}-chmod", std::process::id()));
+        let file = dir.join("run.sh");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut index = Index::open_memory().unwrap();
+        let roots = [dir.to_string_lossy().into_owned()];
+        index.rebuild(&roots, &[], WalkOptions::default()).unwrap();
+        let path = file.to_str().unwrap();
+        assert_eq!(index.lookup_by_path(path).unwrap().mode() & 0o777, 0o644);
+
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+        index.update_path(&file, None, WalkOptions::default());
+        let entry = index.lookup_by_path(path).unwrap();
+        assert_eq!(entry.mode() & 0o777, 0o755);
+        assert!(entry.is_executable());
+
+        // `touch` only moves the mtime.
+        assert!(index.touch(path, 1_000_000_000).unwrap());
+        let entry = index.lookup_by_path(path).unwrap();
+        assert_eq!((entry.mtime(), entry.mode() & 0o777), (1_000_000_000, 0o755));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+//! Index removable media (USB drives, SD cards) as they are plugged in, using udev.
+//!
+//! Each partition with a filesystem on a removable or USB disk is mounted read-only
+//! under the temp directory (or, if something else already mounted it, used where it
+//! is) and added as a root with `Index::rebuild_root_only`. When the device goes
+//! away its root is removed with `Index::remove_root`. Mounting needs root.
+
+use crate::{Index, WalkOptions};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// A removable-media root added by `watch_removable_media`.
+struct MediaRoot {
+    root: PathBuf,
+    /// Whether we mounted it, and so must unmount it.
+    mounted_here: bool,
+}
+
+/// Watch udev for removable block devices until the monitor socket fails, indexing
+/// each one under `excludes` and `walk`. Blocks; run it on its own thread.
+pub fn watch_removable_media(
+    index: Arc<Mutex<Index>>,
+    excludes: Vec<String>,
+    walk: WalkOptions,
+) -> Result<()> {
+    let socket = udev::MonitorBuilder::new()?
+        .match_subsystem_devtype("block", "partition")?
+        .listen()?;
+    // Keyed by device node, since removal events carry little else.
+    let mut roots: HashMap<PathBuf, MediaRoot> = HashMap::new();
+
+    loop {
+        let mut fds = libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `fds` is a valid pollfd and the count is 1.
+        let rc = unsafe { libc::poll(&mut fds, 1, -1) };
+        if rc < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+
+        for event in socket.iter() {
+            let Some(devnode) = event.devnode().map(Path::to_path_buf) else {
+                continue;
+            };
+            match event.event_type() {
+                udev::EventType::Add if is_removable_filesystem(&event) => {
+                    match add_media(&index, &devnode, &excludes, walk) {
+                        Ok(media) => {
+                            roots.insert(devnode, media);
+                        }
+                        Err(e) => {
+                            tracing::warn!(device = %devnode.display(), error = %e, "failed to index removable media");
+                        }
+                    }
+                }
+                udev::EventType::Remove => {
+                    if let Some(media) = roots.remove(&devnode) {
+                        remove_media(&index, &devnode, &media);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A partition holding a filesystem, on a disk that is removable or on USB.
+fn is_removable_filesystem(dev: &udev::Device) -> bool {
+    if dev.property_value("ID_FS_USAGE") != Some(OsStr::new("filesystem")) {
+        return false;
+    }
+    let usb = dev.property_value("ID_BUS") == Some(OsStr::new("usb"));
+    // Card readers often skip ID_BUS; the parent disk's `removable` flag covers them.
+    let removable = dev
+        .parent()
+        .is_some_and(|disk| disk.attribute_value("removable") == Some(OsStr::new("1")));
+    usb || removable
+}
+
+fn add_media(
+    index: &Mutex<Index>,
+    devnode: &Path,
+    excludes: &[String],
+    walk: WalkOptions,
+) -> Result<MediaRoot> {
+    let media = match existing_mount(devnode)? {
+        Some(root) => MediaRoot {
+            root,
+            mounted_here: false,
+        },
+        None => {
+            let name = devnode.file_name().unwrap_or(devnode.as_os_str());
+            let root = std::env::temp_dir().join(format!("les-media-{}", name.to_string_lossy()));
+            fs::create_dir_all(&root)?;
+            let status = Command::new("mount")
+                .args(["-o", "ro,nosuid,nodev,noexec"])
+                .arg(devnode)
+                .arg(&root)
+                .status()?;
+            if !status.success() {
+                let _ = fs::remove_dir(&root);
+                anyhow::bail!("mount exited with {status}");
+            }
+            MediaRoot {
+                root,
+                mounted_here: true,
+            }
+        }
+    };
+
+    let root = media.root.to_string_lossy();
+    let added = index
+        .lock()
+        .unwrap()
+        .rebuild_root_only(&root, excludes, walk)?;
+    tracing::info!(device = %devnode.display(), %root, added, "indexed removable media");
+    Ok(media)
+}
+
+fn remove_media(index: &Mutex<Index>, devnode: &Path, media: &MediaRoot) {
+    let root = media.root.to_string_lossy();
+    match index.lock().unwrap().remove_root(&root) {
+        Ok(removed) => {
+            tracing::info!(device = %devnode.display(), %root, removed, "removed removable media")
+        }
+        Err(e) => tracing::warn!(%root, error = %e, "failed to remove media root"),
+    }
+    if media.mounted_here {
+        // The device is already gone, so only a lazy unmount can succeed.
+        match Command::new("umount").arg("-l").arg(&media.root).status() {
+            Ok(status) if status.success() => {
+                let _ = fs::remove_dir(&media.root);
+            }
+            Ok(status) => tracing::warn!(%root, "umount exited with {status}"),
+            Err(e) => tracing::warn!(%root, error = %e, "failed to run umount"),
+        }
+    }
+}
+
+/// Where `devnode` is already mounted, from `/proc/self/mounts`.
+fn existing_mount(devnode: &Path) -> Result<Option<PathBuf>> {
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    let devnode = devnode.to_string_lossy();
+    Ok(mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != devnode {
+            return None;
+        }
+        // Spaces and the like are octal-escaped, e.g. `/media/USB\040DISK`.
+        fields.next().map(|p| PathBuf::from(unescape_mount_path(p)))
+    }))
+}
+
+fn unescape_mount_path(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(digits, 8) {
+                out.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,62 @@
+//! Ordered index of lowercased file names, used to narrow `PatternMode::Prefix`
+//! queries to the entries whose name starts with the prefix.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// `(lowercased file name, position in Index::entries)`, sorted by name, so the
+/// names sharing a prefix form one contiguous range.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PrefixIndex {
+    names: BTreeSet<(String, u32)>,
+}
+
+/// The key an entry is filed under; `None` for paths without a file name (`/`).
+fn name_key(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .map(str::to_lowercase)
+}
+
+impl PrefixIndex {
+    pub(crate) fn build<'a>(paths: impl Iterator<Item = &'a str>) -> Self {
+        let names = paths
+            .enumerate()
+            .filter_map(|(i, path)| Some((name_key(path)?, i as u32)))
+            .collect();
+        Self { names }
+    }
+
+    /// Record `path` at position `pos`.
+    pub(crate) fn add(&mut self, pos: usize, path: &str) {
+        if let Some(name) = name_key(path) {
+            self.names.insert((name, pos as u32));
+        }
+    }
+
+    /// Forget `path` at position `pos`.
+    pub(crate) fn remove(&mut self, pos: usize, path: &str) {
+        if let Some(name) = name_key(path) {
+            self.names.remove(&(name, pos as u32));
+        }
+    }
+
+    /// Positions of entries whose file name starts with the part of `pattern` after
+    /// its last `/`, case-insensitively, in increasing order. `None` if that part is
+    /// empty, since every entry would be a candidate.
+    pub(crate) fn candidates(&self, pattern: &str) -> Option<Vec<usize>> {
+        let prefix = pattern.rsplit('/').next().unwrap_or(pattern).to_lowercase();
+        if prefix.is_empty() {
+            return None;
+        }
+        let mut out: Vec<usize> = self
+            .names
+            .range((prefix.clone(), 0)..)
+            .take_while(|(name, _)| name.starts_with(&prefix))
+            .map(|&(_, pos)| pos as usize)
+            .collect();
+        out.sort_unstable();
+        Some(out)
+    }
+}
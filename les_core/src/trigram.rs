@@ -0,0 +1,107 @@
+//! Trigram inverted index over lowercased entry paths, used to narrow substring
+//! queries to a candidate set before the full `contains` scan.
+
+use crate::SerdeFormat;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+type Trigram = [u8; 3];
+
+/// Maps each trigram of a lowercased path to the sorted positions (in
+/// `Index::entries`) of the entries containing it.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct TrigramIndex {
+    postings: HashMap<Trigram, Vec<u32>>,
+}
+
+fn trigrams(path: &str) -> HashSet<Trigram> {
+    path.to_lowercase()
+        .as_bytes()
+        .windows(3)
+        .map(|w| [w[0], w[1], w[2]])
+        .collect()
+}
+
+impl TrigramIndex {
+    pub(crate) fn build<'a>(paths: impl Iterator<Item = &'a str>) -> Self {
+        let mut index = Self::default();
+        for (i, path) in paths.enumerate() {
+            // Positions are appended in increasing order, so postings stay sorted.
+            for t in trigrams(path) {
+                index.postings.entry(t).or_default().push(i as u32);
+            }
+        }
+        index
+    }
+
+    /// Record `path` at position `pos`.
+    pub(crate) fn add(&mut self, pos: usize, path: &str) {
+        let pos = pos as u32;
+        for t in trigrams(path) {
+            let list = self.postings.entry(t).or_default();
+            if let Err(at) = list.binary_search(&pos) {
+                list.insert(at, pos);
+            }
+        }
+    }
+
+    /// Forget `path` at position `pos`.
+    pub(crate) fn remove(&mut self, pos: usize, path: &str) {
+        let pos = pos as u32;
+        for t in trigrams(path) {
+            if let Some(list) = self.postings.get_mut(&t) {
+                if let Ok(at) = list.binary_search(&pos) {
+                    list.remove(at);
+                }
+                if list.is_empty() {
+                    self.postings.remove(&t);
+                }
+            }
+        }
+    }
+
+    /// Positions of entries that may contain `needle` (case-insensitively), in
+    /// increasing order. `None` if the needle is too short to narrow anything.
+    pub(crate) fn candidates(&self, needle: &str) -> Option<Vec<usize>> {
+        let grams = trigrams(needle);
+        if grams.is_empty() {
+            return None;
+        }
+        let mut lists = Vec::with_capacity(grams.len());
+        for t in &grams {
+            match self.postings.get(t) {
+                Some(list) => lists.push(list.as_slice()),
+                None => return Some(Vec::new()),
+            }
+        }
+        // Intersect starting from the shortest list.
+        lists.sort_by_key(|l| l.len());
+        let mut out: Vec<u32> = lists[0].to_vec();
+        for list in &lists[1..] {
+            out.retain(|p| list.binary_search(p).is_ok());
+            if out.is_empty() {
+                break;
+            }
+        }
+        Some(out.into_iter().map(|p| p as usize).collect())
+    }
+
+    pub(crate) fn save(&self, tree: &sled::Tree, format: SerdeFormat) -> Result<()> {
+        tree.clear()?;
+        for (t, list) in &self.postings {
+            tree.insert(t, format.encode(list)?)?;
+        }
+        tree.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn load(tree: &sled::Tree, format: SerdeFormat) -> Result<Self> {
+        let mut postings = HashMap::new();
+        for item in tree.iter() {
+            let (k, v) = item?;
+            let t: Trigram = <[u8; 3]>::try_from(k.as_ref())?;
+            postings.insert(t, format.decode(&v)?);
+        }
+        Ok(Self { postings })
+    }
+}
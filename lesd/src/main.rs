@@ -1,42 +1,149 @@
 use anyhow::Result;
 use clap::Parser;
-use les_core::{Index, Query, QueryResult};
+use les_core::{
+    build_exclude_set, read_frame, write_frame, ExtensionStats, FileEntry, GcStats, Index,
+    IndexConfig, IndexEvent, IndexStats, Query,
+    QueryResult, RebuildStats, SerdeFormat, WalkOptions,
+};
+use notify::event::ModifyKind;
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
-use std::{fs, io::Read, io::Write, sync::{Arc, Mutex}, thread};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use std::io::{BufRead, BufReader, Read};
+use std::{fs, io::Write, sync::{Arc, Mutex}, thread};
 use tokio::signal;
+use tokio::sync::broadcast;
+use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(name = "lesd", about = "Linux Everything-style search daemon")]
 struct Args {
-    /// Roots to index (default: /)
-    #[arg(long, num_args = 1.., default_values_t = [String::from("/")])]
+    /// Roots to index (default: / when no --roots-file is given either)
+    #[arg(long, num_args = 1..)]
     roots: Vec<String>,
 
+    /// File with one root per line (blank lines and `#` comments ignored), merged with --roots
+    #[arg(long)]
+    roots_file: Option<PathBuf>,
+
     /// Path to index database
     #[arg(long, default_value = "/var/lib/les/index.db")]
     db_path: String,
 
+    /// sled page cache size in MiB
+    #[arg(long, default_value_t = 128)]
+    sled_cache_mb: usize,
+
+    /// How values are stored in the database: bincode, msgpack or json. A database in
+    /// another format is converted on startup
+    #[arg(long, default_value = "bincode")]
+    db_format: SerdeFormat,
+
+    /// Keep a trigram index to speed up substring queries (uses more memory)
+    #[arg(long)]
+    trigram_index: bool,
+
+    /// Keep an ordered index of file names to speed up prefix queries (uses more memory)
+    #[arg(long)]
+    prefix_index: bool,
+
+    /// Stop indexing a root once the in-memory index is estimated to exceed this
+    #[arg(long, value_name = "N")]
+    max_memory_mb: Option<usize>,
+
+    /// Warn (and report `lesd_db_size_bytes_exceeded 1` on `/metrics`) once the
+    /// database on disk grows past this many GiB; checked every minute
+    #[arg(long, value_name = "N")]
+    max_db_size_gb: Option<u64>,
+
     /// Unix socket path for client communication
     #[arg(long, default_value = "/run/lesd.sock")]
     socket: String,
 
+    /// Permissions for the socket file, in octal. 0o600 lets only the daemon's user connect,
+    /// 0o660 adds its group, 0o666 lets every local user query the whole index
+    #[arg(long, default_value = "0o600", value_parser = parse_socket_mode)]
+    socket_mode: u32,
+
     /// Rebuild the index on start
     #[arg(long)]
     rebuild: bool,
 
-    /// Exclude patterns (substring match)
+    /// Exclude patterns (matched anywhere in the path; globs allowed)
     #[arg(long)]
     exclude: Vec<String>,
+
+    /// Only apply excludes this many path components below a root and deeper
+    #[arg(long, default_value_t = 0)]
+    exclude_min_depth: usize,
+
+    /// Don't index entries fewer than this many components below a root
+    #[arg(long, default_value_t = 0)]
+    walk_min_depth: usize,
+
+    /// Don't descend more than this many components below a root
+    #[arg(long)]
+    walk_max_depth: Option<usize>,
+
+    /// Descend into symlinked directories (each directory is still walked once)
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Store the first and last 64 bytes of each file (for `les --starts-with`)
+    #[arg(long)]
+    fingerprint: bool,
+
+    /// File with one exclude pattern per line, merged with --exclude
+    #[arg(long)]
+    exclude_file: Option<PathBuf>,
+
+    /// Poll interval for the filesystem watcher, in milliseconds
+    #[arg(long, default_value_t = 200)]
+    watch_debounce_ms: u64,
+
+    /// Compare file contents to detect changes (e.g. editors writing in place)
+    #[arg(long)]
+    watch_compare_contents: bool,
+
+    /// Only serve clients with these UIDs, e.g. 0,1000 (default: anyone who can open the socket)
+    #[arg(long, value_delimiter = ',')]
+    allowed_uids: Vec<u32>,
+
+    /// Once serving, write the current epoch seconds to this file; removed on shutdown
+    #[arg(long, value_name = "PATH")]
+    ready_file: Option<PathBuf>,
+
+    /// Once serving, send READY=1 to systemd (for Type=notify units)
+    #[arg(long)]
+    sd_notify: bool,
+
+    /// Mount and index USB drives and SD cards as they are plugged in (needs root)
+    #[cfg(feature = "udev")]
+    #[arg(long)]
+    index_removable_media: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Request {
-    Query { query: Query },
+    Query { query: Box<Query> },
+    /// `since` (UNIX epoch seconds) also counts the entries modified since then.
+    Stats {
+        #[serde(default)]
+        since: Option<i64>,
+    },
+    Rebuild,
+    /// Drop entries whose paths no longer exist (`Index::gc`).
+    Gc,
+    ExtensionStats,
     Ping,
+    /// Stream an `Event` for every watcher update until `Unsubscribe` or disconnect.
+    Subscribe,
+    Unsubscribe,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,26 +151,173 @@ enum Request {
 enum Response {
     Pong,
     QueryResult(QueryResult),
+    Stats(IndexStats),
+    Rebuilt(RebuildStats),
+    Collected(GcStats),
+    ExtensionStats { extensions: Vec<ExtensionStats> },
+    Event(IndexEvent),
     Error { message: String },
 }
 
-fn handle_client(mut stream: UnixStream, index: Arc<Mutex<Index>>) -> Result<()> {
-    let mut buf = String::new();
-    stream.read_to_string(&mut buf)?;
+/// Events a slow subscriber may fall behind by before it starts missing some.
+const EVENT_BUFFER: usize = 1024;
 
-    let req: Request = match serde_json::from_str(&buf) {
-        Ok(r) => r,
-        Err(e) => {
-            let resp = Response::Error {
-                message: format!("Invalid request: {e}"),
-            };
-            let _ = stream.write_all(serde_json::to_string(&resp)?.as_bytes());
+fn handle_client(
+    stream: UnixStream,
+    index: Arc<Mutex<Index>>,
+    args: Arc<Args>,
+    events: broadcast::Sender<IndexEvent>,
+) -> Result<()> {
+    let authorized = if args.allowed_uids.is_empty() {
+        true
+    } else {
+        let uid = peer_uid(&stream)?;
+        let ok = args.allowed_uids.contains(&uid);
+        if !ok {
+            tracing::warn!(uid, "rejecting client with unauthorized uid");
+        }
+        ok
+    };
+    // Unauthorized clients get one error reply in whichever framing they used.
+    let respond = |req: serde_json::Result<Request>| match req {
+        _ if !authorized => Response::Error {
+            message: "unauthorized".to_string(),
+        },
+        Ok(req) => dispatch(req, &index, &args),
+        Err(e) => Response::Error {
+            message: format!("Invalid request: {e}"),
+        },
+    };
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    // Legacy clients send a bare JSON object, possibly over several lines, and then
+    // shut down their write side; a framed request never starts with `{`.
+    let first = reader.fill_buf()?.first().copied();
+    match first {
+        None => return Ok(()),
+        Some(b) if b == b'{' || b.is_ascii_whitespace() => {
+            let mut buf = String::new();
+            reader.read_to_string(&mut buf)?;
+            let resp = respond(serde_json::from_str(&buf));
+            writer.write_all(serde_json::to_string(&resp)?.as_bytes())?;
             return Ok(());
         }
+        Some(_) => {}
+    }
+
+    while let Some(body) = read_frame(&mut reader)? {
+        let req = serde_json::from_slice(&body);
+        if authorized && matches!(req, Ok(Request::Subscribe)) {
+            return serve_subscription(reader, writer, events.subscribe());
+        }
+        let resp = respond(req);
+        write_frame(&mut writer, serde_json::to_string(&resp)?.as_bytes())?;
+        if !authorized {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// UID of the process on the other end of `stream`, via `SO_PEERCRED`.
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
     };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes and sized for SO_PEERCRED.
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Forward index events to a subscribed client from a writer thread, while this
+/// thread waits for `Unsubscribe` (or the client hanging up) and then closes the
+/// connection. Events missed by a lagging client are logged, not replayed.
+fn serve_subscription(
+    mut reader: BufReader<UnixStream>,
+    mut writer: UnixStream,
+    mut rx: broadcast::Receiver<IndexEvent>,
+) -> Result<()> {
+    let closer = writer.try_clone()?;
+    thread::spawn(move || loop {
+        let event = match rx.blocking_recv() {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "subscriber fell behind, dropping events");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let sent = serde_json::to_string(&Response::Event(event))
+            .map_err(anyhow::Error::from)
+            .and_then(|data| write_frame(&mut writer, data.as_bytes()));
+        if sent.is_err() {
+            break;
+        }
+    });
+
+    while let Some(body) = read_frame(&mut reader)? {
+        match serde_json::from_slice(&body) {
+            Ok(Request::Unsubscribe) => break,
+            _ => tracing::warn!("ignoring request on a subscribed connection"),
+        }
+    }
+    // The writer thread finds the socket closed on its next event and exits.
+    let _ = closer.shutdown(std::net::Shutdown::Both);
+    Ok(())
+}
 
-    let resp = match req {
+fn dispatch(req: Request, index: &Mutex<Index>, args: &Args) -> Response {
+    match req {
         Request::Ping => Response::Pong,
+        Request::Subscribe => Response::Error {
+            message: "subscribe needs a length-framed connection".to_string(),
+        },
+        Request::Unsubscribe => Response::Error {
+            message: "not subscribed".to_string(),
+        },
+        Request::Stats { since } => {
+            let idx = index.lock().unwrap();
+            let mut stats = idx.stats();
+            stats.recent_changes = since.map(|since| idx.recent_changes(since).count());
+            Response::Stats(stats)
+        }
+        Request::ExtensionStats => Response::ExtensionStats {
+            extensions: index.lock().unwrap().extension_stats(),
+        },
+        Request::Rebuild => {
+            let mut idx = index.lock().unwrap();
+            match idx.rebuild(&args.roots, &args.exclude, walk_options(args)) {
+                Ok(stats) => {
+                    log_rebuild_stats(&stats, idx.last_roots());
+                    Response::Rebuilt(stats)
+                }
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            }
+        }
+        Request::Gc => match index.lock().unwrap().gc() {
+            Ok(stats) => Response::Collected(stats),
+            Err(e) => Response::Error {
+                message: e.to_string(),
+            },
+        },
         Request::Query { query } => {
             let idx = index.lock().unwrap();
             match idx.run_query(&query) {
@@ -73,15 +327,170 @@ fn handle_client(mut stream: UnixStream, index: Arc<Mutex<Index>>) -> Result<()>
                 },
             }
         }
-    };
+    }
+}
+
+/// How often `start_db_size_monitor` measures the database.
+const DB_SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Latest database size seen by `start_db_size_monitor`, served on `/metrics`.
+#[derive(Default)]
+struct DbUsage {
+    bytes: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+/// Measure the database every `DB_SIZE_CHECK_INTERVAL`, warning when it passes 90%
+/// of `max_bytes` and again when it passes `max_bytes` itself.
+fn start_db_size_monitor(index: Arc<Mutex<Index>>, usage: Arc<DbUsage>, max_bytes: Option<u64>) {
+    // Walking the database can take a while; don't block queries for it.
+    let db_path = index.lock().unwrap().db_path().to_path_buf();
+    thread::spawn(move || {
+        // Only warn when the size moves into a higher band, not every minute.
+        let mut warned_near = false;
+        let mut warned_over = false;
+        loop {
+            let size = les_core::db_size_on_disk(&db_path);
+            match size {
+                Ok(bytes) => {
+                    usage.bytes.store(bytes, Ordering::Relaxed);
+                    if let Some(max) = max_bytes {
+                        let over = bytes > max;
+                        let near = bytes > max / 10 * 9;
+                        usage.exceeded.store(over, Ordering::Relaxed);
+                        if over && !warned_over {
+                            tracing::warn!(bytes, max, "database is larger than --max-db-size-gb");
+                        } else if near && !warned_near && !over {
+                            tracing::warn!(
+                                bytes,
+                                max,
+                                "database is above 90% of --max-db-size-gb; add --exclude patterns \
+                                 or rebuild with --max-memory-mb to keep it smaller"
+                            );
+                        }
+                        warned_over = over;
+                        warned_near = near;
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "failed to measure database size"),
+            }
+            thread::sleep(DB_SIZE_CHECK_INTERVAL);
+        }
+    });
+}
+
+/// Serve a fixed HTTP health response on `path`, e.g. for
+/// `curl --unix-socket /run/lesd.sock.health http://localhost/health`, and
+/// Prometheus gauges on `/metrics`.
+fn start_health_listener(
+    path: &Path,
+    index: Arc<Mutex<Index>>,
+    usage: Arc<DbUsage>,
+    sled_cache_bytes: u64,
+) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let watch_count = index.lock().unwrap().watch_counter();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_health(stream, &index, &usage, &watch_count, sled_cache_bytes) {
+                        eprintln!("health check error: {e}");
+                    }
+                }
+                Err(e) => eprintln!("health accept error: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_health(
+    stream: UnixStream,
+    index: &Mutex<Index>,
+    usage: &DbUsage,
+    watch_count: &AtomicUsize,
+    sled_cache_bytes: u64,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // Drain the request head; only the path of the request line is looked at.
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
 
-    let out = serde_json::to_string(&resp)?;
-    stream.write_all(out.as_bytes())?;
+    let (content_type, body) = if request_line.split_whitespace().nth(1) == Some("/metrics") {
+        let db_size = usage.bytes.load(Ordering::Relaxed);
+        let body = format!(
+            "# TYPE lesd_db_size_bytes gauge\nlesd_db_size_bytes {db_size}\n\
+             # TYPE lesd_index_size_bytes gauge\nlesd_index_size_bytes {db_size}\n\
+             # TYPE lesd_db_size_bytes_exceeded gauge\nlesd_db_size_bytes_exceeded {}\n\
+             # TYPE lesd_watch_count gauge\nlesd_watch_count {}\n\
+             # TYPE lesd_sled_cache_bytes gauge\nlesd_sled_cache_bytes {}\n",
+            u8::from(usage.exceeded.load(Ordering::Relaxed)),
+            watch_count.load(Ordering::Relaxed),
+            sled_cache_bytes
+        );
+        ("text/plain; version=0.0.4", body)
+    } else {
+        // A rebuild holds the lock for a long time; report that instead of blocking the probe.
+        let body = match index.try_lock() {
+            Ok(idx) => format!(r#"{{"status":"ok","entries":{}}}"#, idx.entries.len()),
+            Err(_) => r#"{"status":"busy"}"#.to_string(),
+        };
+        ("application/json", body)
+    };
+    let mut writer = stream;
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
     Ok(())
 }
 
-fn start_fs_watcher(index: Arc<Mutex<Index>>, roots: Vec<String>, excludes: Vec<String>) -> Result<()> {
-    let excludes_arc = Arc::new(excludes);
+fn log_rebuild_stats(stats: &RebuildStats, roots: &[String]) {
+    eprintln!(
+        "Index built: {} entries in {:.2?} ({} dirs skipped, {} errors)",
+        stats.entries_added,
+        stats.duration,
+        stats.dirs_skipped,
+        stats.error_count
+    );
+    for (path, message) in &stats.errors {
+        if roots.contains(path) {
+            eprintln!("warning: {path}: {message}");
+        }
+    }
+}
+
+fn walk_options(args: &Args) -> WalkOptions {
+    WalkOptions {
+        exclude_min_depth: args.exclude_min_depth,
+        walk_min_depth: args.walk_min_depth,
+        walk_max_depth: args.walk_max_depth,
+        follow_symlinks: args.follow_symlinks,
+        fingerprint: args.fingerprint,
+    }
+}
+
+fn start_fs_watcher(
+    index: Arc<Mutex<Index>>,
+    events: broadcast::Sender<IndexEvent>,
+    roots: Vec<String>,
+    excludes: Vec<String>,
+    walk: WalkOptions,
+    config: Config,
+) -> Result<()> {
+    let excludes_arc = build_exclude_set(&excludes)?.map(Arc::new);
+    let watch_count = index.lock().unwrap().watch_counter();
 
     thread::spawn(move || {
         let index_arc = index;
@@ -91,12 +500,21 @@ fn start_fs_watcher(index: Arc<Mutex<Index>>, roots: Vec<String>, excludes: Vec<
             move |res: notify::Result<Event>| match res {
                 Ok(event) => {
                     if let Some(path) = event.paths.first() {
-                        if matches!(
+                        // inotify reports touch and chmod alike as `Metadata(Any)`;
+                        // `touch_path` tells them apart by what the stat changed.
+                        if let EventKind::Modify(ModifyKind::Metadata(_)) = event.kind {
+                            let mut idx = index_arc.lock().unwrap();
+                            if !touch_path(&mut idx, path) {
+                                idx.update_path(path, excludes_inner.as_deref(), walk);
+                            }
+                            publish_event(&events, &idx, path);
+                        } else if matches!(
                             event.kind,
                             EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)
                         ) {
                             let mut idx = index_arc.lock().unwrap();
-                            idx.update_path(path, &excludes_inner);
+                            idx.update_path(path, excludes_inner.as_deref(), walk);
+                            publish_event(&events, &idx, path);
                         }
                     }
                 }
@@ -104,13 +522,16 @@ fn start_fs_watcher(index: Arc<Mutex<Index>>, roots: Vec<String>, excludes: Vec<
                     eprintln!("watch error: {err}");
                 }
             },
-            Config::default(),
+            config,
         )
         .expect("failed to create watcher");
 
         for r in &roots {
-            if let Err(e) = watcher.watch(Path::new(r), RecursiveMode::Recursive) {
-                eprintln!("Failed to watch {}: {e}", r);
+            match watcher.watch(Path::new(r), RecursiveMode::Recursive) {
+                Ok(()) => {
+                    watch_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+                Err(e) => eprintln!("Failed to watch {}: {e}", r),
             }
         }
 
@@ -123,33 +544,186 @@ fn start_fs_watcher(index: Arc<Mutex<Index>>, roots: Vec<String>, excludes: Vec<
     Ok(())
 }
 
+/// Tell subscribers, if there are any, how `path` is indexed after an update.
+fn publish_event(events: &broadcast::Sender<IndexEvent>, index: &Index, path: &Path) {
+    if events.receiver_count() == 0 {
+        return;
+    }
+    let path = path.to_string_lossy().into_owned();
+    let entry = index.lookup_by_path(&path).cloned();
+    let kind = if entry.is_some() {
+        "updated"
+    } else {
+        "removed"
+    };
+    // Only fails when every subscriber has gone away meanwhile.
+    let _ = events.send(IndexEvent {
+        kind: kind.to_string(),
+        path,
+        entry,
+    });
+}
+
+/// Refresh just the mtime of an indexed `path` if nothing else it records changed;
+/// `false` if a full update is needed (e.g. after a chmod).
+fn touch_path(index: &mut Index, path: &Path) -> bool {
+    let s = path.to_string_lossy();
+    let (Some(old), Ok(new)) = (index.lookup_by_path(&s), FileEntry::from_path(path)) else {
+        return false;
+    };
+    let recorded = |e: &FileEntry| (e.is_dir(), e.size(), e.mode(), e.dev(), e.ino());
+    if recorded(old) != recorded(&new) {
+        return false;
+    }
+    match index.touch(&s, new.mtime()) {
+        Ok(touched) => touched,
+        Err(e) => {
+            eprintln!("Failed to update mtime of {}: {e}", path.display());
+            false
+        }
+    }
+}
+
+/// Read a list file: one value per line, skipping blank lines and `#` comments.
+fn read_list_file(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Parse an octal file mode such as `0o660`, `0660` or `660`.
+fn parse_socket_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        Ok(_) => Err(format!("{s} is not a permission mode (at most 0o777)")),
+        Err(_) => Err(format!("{s} is not an octal mode")),
+    }
+}
+
+/// Warn if the index on disk was built with different roots or excludes than
+/// the ones given now; the watcher and later rebuilds use the new ones.
+fn warn_on_config_mismatch(index: &Index, args: &Args) {
+    let roots: Vec<String> = args
+        .roots
+        .iter()
+        .map(|r| match std::path::absolute(r) {
+            Ok(p) => p.to_string_lossy().to_string(),
+            Err(_) => r.clone(),
+        })
+        .collect();
+    let last_roots = index.last_roots();
+    if !last_roots.is_empty() && last_roots != roots.as_slice() {
+        tracing::warn!(
+            ?last_roots,
+            ?roots,
+            "index was built with different roots; pass --rebuild to reindex"
+        );
+    }
+    if let Some(last_excludes) = index.last_excludes() {
+        if last_excludes != args.exclude.as_slice() {
+            tracing::warn!(
+                ?last_excludes,
+                excludes = ?args.exclude,
+                "index was built with different excludes; pass --rebuild to reindex"
+            );
+        }
+    }
+}
+
+/// Merge `--roots-file` / `--exclude-file` into the CLI lists.
+fn resolve_lists(args: &mut Args) -> Result<()> {
+    if let Some(path) = &args.roots_file {
+        for root in read_list_file(path)? {
+            if !Path::new(&root).exists() {
+                anyhow::bail!("root {root} listed in {} does not exist", path.display());
+            }
+            args.roots.push(root);
+        }
+    }
+    if args.roots.is_empty() {
+        args.roots.push(String::from("/"));
+    }
+    if let Some(path) = &args.exclude_file {
+        args.exclude.extend(read_list_file(path)?);
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    resolve_lists(&mut args)?;
+    let args = Arc::new(args);
+
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
 
     // Ensure DB directory exists
     if let Some(parent) = Path::new(&args.db_path).parent() {
         fs::create_dir_all(parent)?;
     }
 
-    let mut index = Index::open(Path::new(&args.db_path))?;
+    let config = IndexConfig {
+        sled_cache_mb: args.sled_cache_mb,
+        trigram_index: args.trigram_index,
+        prefix_index: args.prefix_index,
+        format: args.db_format,
+        max_memory_mb: args.max_memory_mb,
+    };
+    let mut index = Index::open_with_config(Path::new(&args.db_path), config)?;
 
     if args.rebuild || index.entries.is_empty() {
         eprintln!("Building index from scratch...");
-        index.rebuild(&args.roots, &args.exclude)?;
-        eprintln!("Index built: {} entries", index.entries.len());
+        let stats = index
+            .rebuild_async(args.roots.clone(), args.exclude.clone(), walk_options(&args))
+            .await?;
+        log_rebuild_stats(&stats, index.last_roots());
     } else {
         eprintln!(
             "Loaded existing index: {} entries from {}",
             index.entries.len(),
             args.db_path
         );
+        warn_on_config_mismatch(&index, &args);
     }
 
     let shared_index = Arc::new(Mutex::new(index));
 
     // FS watcher (basic real-time updates)
-    start_fs_watcher(shared_index.clone(), args.roots.clone(), args.exclude.clone())?;
+    let (events, _) = broadcast::channel(EVENT_BUFFER);
+    let watch_config = Config::default()
+        .with_poll_interval(Duration::from_millis(args.watch_debounce_ms))
+        .with_compare_contents(args.watch_compare_contents);
+    start_fs_watcher(
+        shared_index.clone(),
+        events.clone(),
+        args.roots.clone(),
+        args.exclude.clone(),
+        walk_options(&args),
+        watch_config,
+    )?;
+
+    #[cfg(feature = "udev")]
+    if args.index_removable_media {
+        let index = shared_index.clone();
+        let excludes = args.exclude.clone();
+        let walk = walk_options(&args);
+        thread::spawn(move || {
+            if let Err(e) = les_core::media::watch_removable_media(index, excludes, walk) {
+                tracing::error!(error = %e, "removable media watcher stopped");
+            }
+        });
+    }
 
     // Remove old socket if exists
     let socket_path = PathBuf::from(&args.socket);
@@ -160,18 +734,29 @@ async fn main() -> Result<()> {
         fs::create_dir_all(parent)?;
     }
 
-    let listener = UnixListener::bind(&socket_path)?;
+    // Create the socket with its final mode, so it never exists with the umask's
+    // default permissions. The umask is process-wide; put it back straight away.
+    let socket_umask = !args.socket_mode as libc::mode_t & 0o777;
+    // SAFETY: umask only swaps the process file mode mask and cannot fail.
+    let old_umask = unsafe { libc::umask(socket_umask) };
+    let listener = UnixListener::bind(&socket_path);
+    // SAFETY: as above.
+    unsafe { libc::umask(old_umask) };
+    let listener = listener?;
     eprintln!("lesd listening on {}", args.socket);
 
     // Accept loop in a separate thread
     let index_for_accept = shared_index.clone();
+    let args_for_accept = args.clone();
     thread::spawn(move || {
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
                     let idx = index_for_accept.clone();
+                    let args = args_for_accept.clone();
+                    let events = events.clone();
                     thread::spawn(move || {
-                        if let Err(e) = handle_client(stream, idx) {
+                        if let Err(e) = handle_client(stream, idx, args, events) {
                             eprintln!("client error: {e}");
                         }
                     });
@@ -183,13 +768,50 @@ async fn main() -> Result<()> {
         }
     });
 
+    let health_path = PathBuf::from(format!("{}.health", args.socket));
+    let usage = Arc::new(DbUsage::default());
+    start_db_size_monitor(
+        shared_index.clone(),
+        usage.clone(),
+        args.max_db_size_gb.map(|gb| gb * 1024 * 1024 * 1024),
+    );
+    start_health_listener(
+        &health_path,
+        shared_index.clone(),
+        usage,
+        args.sled_cache_mb as u64 * 1024 * 1024,
+    )?;
+
+    tracing::info!(
+        roots = ?args.roots,
+        entries = shared_index.lock().unwrap().entries.len(),
+        excludes = ?args.exclude,
+        socket = %args.socket,
+        db_path = %args.db_path,
+        watch_debounce_ms = args.watch_debounce_ms,
+        "lesd started"
+    );
+
+    // The index is loaded or built and both sockets accept connections.
+    if let Some(path) = &args.ready_file {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        fs::write(path, format!("{}\n", now.as_secs()))?;
+    }
+    if args.sd_notify {
+        if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+            tracing::warn!(error = %e, "failed to notify systemd");
+        }
+    }
+
     // Wait for Ctrl+C (systemd will also send signals)
     signal::ctrl_c().await?;
     eprintln!("Shutting down lesd");
 
-    // Remove socket on exit
-    if socket_path.exists() {
-        let _ = fs::remove_file(&socket_path);
+    // Remove sockets (and the ready marker) on exit
+    for path in [&socket_path, &health_path].into_iter().chain(&args.ready_file) {
+        if path.exists() {
+            let _ = fs::remove_file(path);
+        }
     }
 
     Ok(())